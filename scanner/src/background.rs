@@ -0,0 +1,84 @@
+use image::Rgb;
+
+use crate::{
+    input::{
+        Rgb16Image,
+        to_u8,
+    },
+    keying::{
+        Keyer,
+        KeyMetric,
+    },
+};
+
+/// Color and tolerance used to tell background from fish.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackgroundKey {
+    pub color: Rgb<u8>,
+    /// pixels closer than this to `color` are background
+    pub distance: f32,
+}
+
+/// Width of the band along the image edges that is assumed to show background, relative to the shorter side.
+const BORDER_BAND: f32 = 0.03;
+
+/// Estimates the backdrop color from the pixels along the image border.
+///
+/// The color is the per channel median of the border band, so a fin or a thumb reaching
+/// into the band doesn't shift it as long as most of the border shows backdrop.
+/// The distance threshold is derived from how much the backdrop pixels scatter around that
+/// median: the 90th percentile of their distances (measured with `metric`), with some headroom.
+pub fn estimate_background(img: &Rgb16Image, metric: KeyMetric) -> BackgroundKey {
+    let samples: Vec<Rgb<u8>> = border_band(img).map(|(x, y)| pixel(img, x, y)).collect();
+    let color = median_color(&samples);
+    let keyer = Keyer::new(metric, color);
+    let distance = distance_from_spread(samples.iter().map(|p| keyer.distance(p)).collect(), metric);
+    BackgroundKey { color, distance }
+}
+
+/// Key for comparing `img` pixel by pixel with `plate`, a photo of the empty backdrop.
+///
+/// The color is the plate's median color (what despill and white balance take as the backdrop).
+/// The distance threshold comes from how much photo and plate differ along the image border,
+/// which is mostly camera noise, the same way `estimate_background` derives it.
+pub fn estimate_plate_key(img: &Rgb16Image, plate: &Rgb16Image, metric: KeyMetric) -> BackgroundKey {
+    let samples: Vec<Rgb<u8>> = plate.pixels().map(|p| Rgb([to_u8(p[0]), to_u8(p[1]), to_u8(p[2])])).collect();
+    let color = median_color(&samples);
+    let distances = border_band(img).map(|(x, y)| {
+        Keyer::new(metric, pixel(plate, x, y)).distance(&pixel(img, x, y))
+    }).collect();
+    BackgroundKey { color, distance: distance_from_spread(distances, metric) }
+}
+
+fn pixel(img: &Rgb16Image, x: u32, y: u32) -> Rgb<u8> {
+    let p = img.get_pixel(x, y);
+    Rgb([to_u8(p[0]), to_u8(p[1]), to_u8(p[2])])
+}
+
+/// coordinates of the pixels in the band along the image edges
+fn border_band(img: &Rgb16Image) -> impl Iterator<Item = (u32, u32)> {
+    let (width, height) = img.dimensions();
+    let band = ((width.min(height) as f32 * BORDER_BAND) as u32).max(1);
+    (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+        .filter(move |&(x, y)| x < band || y < band || x >= width.saturating_sub(band) || y >= height.saturating_sub(band))
+}
+
+/// per channel median
+fn median_color(samples: &[Rgb<u8>]) -> Rgb<u8> {
+    let mut color = [0u8; 3];
+    for (c, value) in color.iter_mut().enumerate() {
+        let mut channel: Vec<u8> = samples.iter().map(|p| p[c]).collect();
+        channel.sort_unstable();
+        *value = channel[channel.len() / 2];
+    }
+    Rgb(color)
+}
+
+/// threshold a bit above the 90th percentile of the backdrop's `distances`
+fn distance_from_spread(mut distances: Vec<f32>, metric: KeyMetric) -> f32 {
+    distances.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let spread = distances[distances.len() * 9 / 10];
+    // the limits scale with the metric: 12..80 for rgb
+    let unit = metric.default_distance() / 20.0;
+    (spread * 1.5 + 4.0 * unit).clamp(12.0 * unit, 80.0 * unit)
+}
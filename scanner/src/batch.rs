@@ -0,0 +1,88 @@
+use crate::{
+    error::ScanError,
+    load_fish_skins,
+    manifest::FishManifest,
+    options::ScanOptions,
+    output::{
+        is_image_file,
+        next_fish_number,
+        publish,
+    },
+};
+
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// What happened to one photo of a batch, or to one fish of it with `ScanOptions::multiple_fish`.
+#[derive(Debug)]
+pub struct BatchEntry {
+    pub input: PathBuf,
+    /// the fish number the skin was saved under
+    pub result: Result<u32, ScanError>,
+    /// the messages of the quality checks that only warned
+    pub warnings: Vec<String>,
+}
+
+/// Scans every image in `in_dir` (sorted by name) and saves the skins to `out_dir`
+/// as `NNNN-colors.png` / `NNNN-normals.png`, numbered after the fish already there.
+///
+/// A photo that fails doesn't stop the batch, its error ends up in the returned entry.
+/// `progress` is called after each photo.
+pub fn scan_directory<P, Q, F>(in_dir: P, out_dir: Q, options: &ScanOptions, mut progress: F) -> io::Result<Vec<BatchEntry>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnMut(&BatchEntry),
+{
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+
+    let mut inputs = Vec::new();
+    for entry in fs::read_dir(in_dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_image_file(&path) {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+
+    let mut number = next_fish_number(out_dir)?;
+    let mut entries = Vec::new();
+    for input in inputs {
+        for entry in scan_photo(&input, options, out_dir, &mut number) {
+            progress(&entry);
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Scans the photo at `input` and publishes its skins to `out_dir`, numbered from `number` on.
+/// `number` ends up at the next free one.
+///
+/// There is one entry per fish, or a single one if the whole photo failed.
+pub fn scan_photo(input: &Path, options: &ScanOptions, out_dir: &Path, number: &mut u32) -> Vec<BatchEntry> {
+    let skins = match load_fish_skins(input, options) {
+        Ok(skins) => skins,
+        Err(err) => return vec![BatchEntry { input: input.to_path_buf(), result: Err(err), warnings: Vec::new() }],
+    };
+    skins.into_iter().map(|skin| {
+        let mut warnings = Vec::new();
+        let result = skin
+            .and_then(|skin| {
+                warnings = skin.quality.warnings().map(|finding| finding.message.clone()).collect();
+                publish(&skin, &FishManifest::new(&skin, Some(input)), out_dir, *number)
+            })
+            .map(|_| *number);
+        if result.is_ok() {
+            *number += 1;
+        }
+        BatchEntry { input: input.to_path_buf(), result, warnings }
+    }).collect()
+}
@@ -0,0 +1,376 @@
+//! Taking the photos: a tethered camera driven through gphoto2, or a folder of earlier
+//! photos that are handed out one by one, so the photo booth can run without a camera.
+
+use crate::output::is_image_file;
+
+use std::{
+    fmt,
+    fs,
+    io::{
+        self,
+        Read,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Child,
+        Command,
+        Stdio,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// what gphoto2 says when there is no camera on the usb bus, which is what a camera that
+/// switched itself off looks like
+const CAMERA_GONE: [&str; 3] = [
+    "No camera found",
+    "Could not find the requested device",
+    "Could not claim the USB device",
+];
+
+/// Everything that can go wrong while taking a photo.
+#[derive(Debug)]
+pub enum CaptureError {
+    /// the photo could not be stored
+    Io(io::Error),
+    /// the camera doesn't answer, most likely it powered itself off
+    CameraOff(String),
+    /// no photo arrived in time
+    Timeout(Duration),
+    /// the capture program failed or isn't installed
+    Failed(String),
+    /// a replay source has handed out all of its photos
+    Exhausted,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Io(err) => write!(f, "i/o error: {}", err),
+            CaptureError::CameraOff(message) => write!(
+                f,
+                "the camera is off or disconnected ({}), switch it on and disable its auto power off",
+                message,
+            ),
+            CaptureError::Timeout(timeout) => write!(f, "no photo within {:.1}s", timeout.as_secs_f32()),
+            CaptureError::Failed(message) => write!(f, "capture failed: {}", message),
+            CaptureError::Exhausted => write!(f, "no photos left to replay"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CaptureError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(err: io::Error) -> Self {
+        CaptureError::Io(err)
+    }
+}
+
+/// Something that takes photos on request.
+pub trait CaptureSource {
+    /// Takes a photo, stores it in `dir` and returns its path.
+    /// Gives up with `CaptureError::Timeout` if that takes longer than `timeout`.
+    fn capture(&mut self, dir: &Path, timeout: Duration) -> Result<PathBuf, CaptureError>;
+}
+
+/// A camera on usb, through `gphoto2 --capture-image-and-download`.
+pub struct Gphoto2 {
+    /// `--camera`, e.g. "Canon EOS 600D", gphoto2 picks the first camera it finds if `None`
+    pub camera: Option<String>,
+    /// leave the photos on the memory card too, by default they're deleted after downloading
+    pub keep_on_card: bool,
+    /// the gphoto2 executable
+    pub program: PathBuf,
+}
+
+impl Gphoto2 {
+    pub fn new(camera: Option<String>) -> Gphoto2 {
+        Gphoto2 { camera, keep_on_card: false, program: PathBuf::from("gphoto2") }
+    }
+}
+
+impl CaptureSource for Gphoto2 {
+    fn capture(&mut self, dir: &Path, timeout: Duration) -> Result<PathBuf, CaptureError> {
+        fs::create_dir_all(dir)?;
+        let mut command = Command::new(&self.program);
+        if let Some(camera) = &self.camera {
+            command.arg("--camera").arg(camera);
+        }
+        // the camera's own file name, it counts up with every photo
+        command
+            .arg("--capture-image-and-download")
+            .arg("--filename")
+            .arg(dir.join("%f.%C"))
+            .arg("--force-overwrite");
+        if !self.keep_on_card {
+            command.arg("--no-keep");
+        }
+        let child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(CaptureError::Failed(format!("{} not found, is gphoto2 installed?", self.program.display())));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let (success, stdout, stderr) = run_with_timeout(child, timeout)?;
+
+        let output = format!("{}\n{}", stdout, stderr);
+        if let Some(gone) = CAMERA_GONE.iter().find(|message| output.contains(*message)) {
+            return Err(CaptureError::CameraOff(gone.to_string()));
+        }
+        if !success {
+            // gphoto2 reports errors as `*** Error ... ***` lines, or a bare `*** Error ***` and the
+            // message on the next line, followed by hints that don't help here
+            let mut lines = output
+                .lines()
+                .map(|line| line.trim().trim_matches('*').trim())
+                .filter(|line| !line.is_empty())
+                .skip_while(|line| !line.starts_with("Error") && !line.starts_with("An error"));
+            let message = match lines.next() {
+                Some("Error") => lines.next().unwrap_or("Error"),
+                Some(line) => line,
+                None => "gphoto2 exited with an error",
+            };
+            return Err(CaptureError::Failed(message.to_string()));
+        }
+        // the path as passed with --filename, with raw + jpeg the camera delivers both and the first image does
+        stdout
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("Saving file as "))
+            .map(PathBuf::from)
+            .find(|path| is_image_file(path))
+            .ok_or_else(|| CaptureError::Failed("gphoto2 didn't download a photo".to_string()))
+    }
+}
+
+/// Waits for `child` to exit and collects its output, kills it once `timeout` has passed.
+fn run_with_timeout(mut child: Child, timeout: Duration) -> Result<(bool, String, String), CaptureError> {
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(CaptureError::Timeout(timeout));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    Ok((status.success(), stdout, stderr))
+}
+
+/// Reads a pipe of the child on the side, a full pipe would block it.
+fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut text = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut text);
+        }
+        text
+    })
+}
+
+/// Hands out the photos of a folder in name order, as if a camera took them: each capture
+/// copies the next one into the download directory.
+pub struct Replay {
+    photos: Vec<PathBuf>,
+    next: usize,
+    /// start over after the last photo instead of failing with `CaptureError::Exhausted`
+    pub repeat: bool,
+    /// how long a capture takes, captures that would take longer than their timeout time out
+    pub delay: Duration,
+    /// act like a camera that powers itself off after this many photos
+    pub power_off_after: Option<usize>,
+    taken: usize,
+}
+
+impl Replay {
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Replay> {
+        let mut photos = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && is_image_file(&path) {
+                photos.push(path);
+            }
+        }
+        photos.sort();
+        Ok(Replay { photos, next: 0, repeat: false, delay: Duration::from_secs(0), power_off_after: None, taken: 0 })
+    }
+}
+
+impl CaptureSource for Replay {
+    fn capture(&mut self, dir: &Path, timeout: Duration) -> Result<PathBuf, CaptureError> {
+        if self.power_off_after.is_some_and(|count| self.taken >= count) {
+            return Err(CaptureError::CameraOff("replay".to_string()));
+        }
+        if self.delay > timeout {
+            thread::sleep(timeout);
+            return Err(CaptureError::Timeout(timeout));
+        }
+        thread::sleep(self.delay);
+        if self.next == self.photos.len() && self.repeat {
+            self.next = 0;
+        }
+        let photo = self.photos.get(self.next).ok_or(CaptureError::Exhausted)?;
+        fs::create_dir_all(dir)?;
+        let target = dir.join(photo.file_name().unwrap());
+        // replaying into the folder the photos come from leaves them where they are
+        if fs::canonicalize(photo).ok() != fs::canonicalize(&target).ok() {
+            fs::copy(photo, &target)?;
+        }
+        self.next += 1;
+        self.taken += 1;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        batch::scan_photo,
+        options::ScanOptions,
+        output::{
+            colors_path,
+            manifest_path,
+            next_fish_number,
+        },
+    };
+
+    use image::{
+        ImageBuffer,
+        Rgb,
+    };
+
+    /// an empty directory of its own for every test, and for every test run
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scanner-capture-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `count` photos of a striped orange fish on the default, almost black backdrop
+    fn fixture_photos(name: &str, count: usize) -> PathBuf {
+        let dir = scratch_dir(name);
+        for i in 0..count {
+            let photo = ImageBuffer::from_fn(240, 160, |x, y| {
+                let (dx, dy) = ((x as f32 - 120.0) / 80.0, (y as f32 - 80.0) / (30.0 + 5.0 * i as f32));
+                if dx * dx + dy * dy > 1.0 {
+                    Rgb([18u8, 18, 18])
+                } else if (x / 8) % 2 == 0 {
+                    Rgb([240, 140, 40])
+                } else {
+                    Rgb([250, 230, 200])
+                }
+            });
+            photo.save(dir.join(format!("photo-{}.png", i + 1))).unwrap();
+        }
+        dir
+    }
+
+    fn file_name(path: &Path) -> String {
+        path.file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn replayed_photos_are_scanned_and_published() {
+        let photos = fixture_photos("publish-photos", 2);
+        let (downloads, out) = (scratch_dir("publish-downloads"), scratch_dir("publish-out"));
+        let options = ScanOptions { output_width: 256, ..ScanOptions::default() };
+        let mut replay = Replay::new(&photos).unwrap();
+        for expected in 1..=2 {
+            let photo = replay.capture(&downloads, Duration::from_secs(1)).unwrap();
+            assert_eq!(photo.parent(), Some(downloads.as_path()));
+            let mut number = next_fish_number(&out).unwrap();
+            let entries = scan_photo(&photo, &options, &out, &mut number);
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].result.as_ref().ok(), Some(&expected), "{:?}", entries[0].result);
+            assert!(colors_path(&out, expected).is_file());
+            assert!(manifest_path(&out, expected).is_file());
+        }
+        assert!(matches!(replay.capture(&downloads, Duration::from_secs(1)), Err(CaptureError::Exhausted)));
+    }
+
+    #[test]
+    fn repeat_starts_over() {
+        let photos = fixture_photos("repeat", 2);
+        let downloads = scratch_dir("repeat-downloads");
+        let mut replay = Replay::new(&photos).unwrap();
+        replay.repeat = true;
+        let names: Vec<String> = (0..5).map(|_| file_name(&replay.capture(&downloads, Duration::from_secs(1)).unwrap())).collect();
+        assert_eq!(names, ["photo-1.png", "photo-2.png", "photo-1.png", "photo-2.png", "photo-1.png"]);
+    }
+
+    #[test]
+    fn camera_powers_off() {
+        let photos = fixture_photos("power-off", 3);
+        let downloads = scratch_dir("power-off-downloads");
+        let mut replay = Replay::new(&photos).unwrap();
+        replay.power_off_after = Some(2);
+        for _ in 0..2 {
+            assert!(replay.capture(&downloads, Duration::from_secs(1)).is_ok());
+        }
+        assert!(matches!(replay.capture(&downloads, Duration::from_secs(1)), Err(CaptureError::CameraOff(_))));
+    }
+
+    /// a gphoto2 stand-in that prints `output` and fails
+    #[cfg(unix)]
+    fn failing_gphoto2(name: &str, output: &str) -> Gphoto2 {
+        use std::os::unix::fs::PermissionsExt;
+        let program = scratch_dir(name).join("gphoto2");
+        fs::write(&program, format!("#!/bin/sh\ncat >&2 <<'EOF'\n{}\nEOF\nexit 1\n", output)).unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        Gphoto2 { program, ..Gphoto2::new(Some("Canon EOS 60OD".to_string())) }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn gphoto2_errors() {
+        let downloads = scratch_dir("gphoto2-downloads");
+        let mut unknown = failing_gphoto2("gphoto2-unknown", "\n*** Error ***\nUnknown model \"Canon EOS 60OD\".\n\n*** Error (-105: 'Unknown model') ***");
+        match unknown.capture(&downloads, Duration::from_secs(5)) {
+            Err(CaptureError::Failed(message)) => assert_eq!(message, "Unknown model \"Canon EOS 60OD\"."),
+            other => panic!("{:?}", other),
+        }
+        let mut off = failing_gphoto2("gphoto2-off", "\n*** Error: No camera found. ***\n");
+        assert!(matches!(off.capture(&downloads, Duration::from_secs(5)), Err(CaptureError::CameraOff(_))));
+    }
+
+    #[test]
+    fn slow_camera_times_out() {
+        let photos = fixture_photos("timeout", 1);
+        let downloads = scratch_dir("timeout-downloads");
+        let mut replay = Replay::new(&photos).unwrap();
+        replay.delay = Duration::from_millis(50);
+        let timeout = Duration::from_millis(10);
+        assert!(matches!(replay.capture(&downloads, timeout), Err(CaptureError::Timeout(t)) if t == timeout));
+        // fast enough with a longer timeout
+        assert!(replay.capture(&downloads, Duration::from_secs(1)).is_ok());
+    }
+}
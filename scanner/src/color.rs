@@ -0,0 +1,214 @@
+//! Color space conversions used for keying.
+
+use image::Rgb;
+
+/// CIE L*a*b* color, D65 white point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// a 16 bit sRGB value in linear light, 0 to 1
+pub fn srgb16_to_linear(c: u16) -> f32 {
+    srgb_to_linear(c as f32 / u16::MAX as f32)
+}
+
+/// linear light to a 16 bit sRGB value, clamped to 0 to 1
+pub fn linear_to_srgb16(c: f32) -> u16 {
+    (linear_to_srgb(c.clamp(0.0, 1.0)) * u16::MAX as f32).round() as u16
+}
+
+const WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+
+fn lab_f(t: f32) -> f32 {
+    if t > 216.0 / 24389.0 {
+        t.cbrt()
+    } else {
+        (24389.0 / 27.0 * t + 16.0) / 116.0
+    }
+}
+
+impl Lab {
+    /// from linear rgb in [0,1]
+    pub fn from_linear(rgb: [f32; 3]) -> Lab {
+        let [r, g, b] = rgb;
+        let x = 0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b;
+        let y = 0.212_672_9 * r + 0.715_152_2 * g + 0.072_175 * b;
+        let z = 0.019_333_9 * r + 0.119_192 * g + 0.950_304_1 * b;
+        let fx = lab_f(x / WHITE[0]);
+        let fy = lab_f(y / WHITE[1]);
+        let fz = lab_f(z / WHITE[2]);
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+
+    pub fn from_srgb(rgb: &Rgb<u8>) -> Lab {
+        Lab::from_linear([
+            srgb_to_linear(rgb[0] as f32 / 255.0),
+            srgb_to_linear(rgb[1] as f32 / 255.0),
+            srgb_to_linear(rgb[2] as f32 / 255.0),
+        ])
+    }
+
+    pub fn chroma(&self) -> f32 {
+        (self.a * self.a + self.b * self.b).sqrt()
+    }
+}
+
+/// CIE76 color difference, the euclidean distance in Lab.
+pub fn delta_e76(c1: &Lab, c2: &Lab) -> f32 {
+    ((c1.l - c2.l).powi(2) + (c1.a - c2.a).powi(2) + (c1.b - c2.b).powi(2)).sqrt()
+}
+
+/// CIEDE2000 color difference (Sharma, Wu, Dalal 2005), with kL = kC = kH = 1.
+pub fn delta_e2000(c1: &Lab, c2: &Lab) -> f32 {
+    use std::f32::consts::PI;
+    let deg = |r: f32| r * 180.0 / PI;
+    let rad = |d: f32| d * PI / 180.0;
+
+    let c_bar = (c1.chroma() + c2.chroma()) / 2.0;
+    let c_bar7 = c_bar.powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + 25f32.powi(7))).sqrt());
+    let a1 = c1.a * (1.0 + g);
+    let a2 = c2.a * (1.0 + g);
+    let chroma1 = (a1 * a1 + c1.b * c1.b).sqrt();
+    let chroma2 = (a2 * a2 + c2.b * c2.b).sqrt();
+    let hue = |b: f32, a: f32| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            let h = deg(b.atan2(a));
+            if h < 0.0 { h + 360.0 } else { h }
+        }
+    };
+    let h1 = hue(c1.b, a1);
+    let h2 = hue(c2.b, a2);
+
+    let dl = c2.l - c1.l;
+    let dc = chroma2 - chroma1;
+    let dh = if chroma1 * chroma2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let dh_big = 2.0 * (chroma1 * chroma2).sqrt() * (rad(dh) / 2.0).sin();
+
+    let l_bar = (c1.l + c2.l) / 2.0;
+    let chroma_bar = (chroma1 + chroma2) / 2.0;
+    let h_bar = if chroma1 * chroma2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0
+        - 0.17 * rad(h_bar - 30.0).cos()
+        + 0.24 * rad(2.0 * h_bar).cos()
+        + 0.32 * rad(3.0 * h_bar + 6.0).cos()
+        - 0.20 * rad(4.0 * h_bar - 63.0).cos();
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let chroma_bar7 = chroma_bar.powi(7);
+    let r_c = 2.0 * (chroma_bar7 / (chroma_bar7 + 25f32.powi(7))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * chroma_bar;
+    let s_h = 1.0 + 0.015 * chroma_bar * t;
+    let r_t = -(rad(2.0 * d_theta)).sin() * r_c;
+
+    let l_term = dl / s_l;
+    let c_term = dc / s_c;
+    let h_term = dh_big / s_h;
+    (l_term * l_term + c_term * c_term + h_term * h_term + r_t * c_term * h_term).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the test data of Sharma, Wu, Dalal: two Lab colors and their CIEDE2000 difference
+    const SHARMA: [([f32; 3], [f32; 3], f32); 34] = [
+        ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+        ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+        ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+        ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -1.1848, -84.8006], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -0.9009, -85.5211], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+        ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0009], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0010], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0011], 7.2195),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0012], 7.2195),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0009, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0010, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0011, -2.4900], 4.7461),
+        ([50.0, 2.5000, 0.0000], [50.0, 0.0, -2.5000], 4.3065),
+        ([50.0, 2.5000, 0.0000], [73.0, 25.0, -18.0], 27.1492),
+        ([50.0, 2.5000, 0.0000], [61.0, -5.0, 29.0], 22.8977),
+        ([50.0, 2.5000, 0.0000], [56.0, -27.0, -3.0], 31.9030),
+        ([50.0, 2.5000, 0.0000], [58.0, 24.0, 15.0], 19.4535),
+        ([50.0, 2.5000, 0.0000], [50.0, 3.1736, 0.5854], 1.0000),
+        ([50.0, 2.5000, 0.0000], [50.0, 3.2972, 0.0000], 1.0000),
+        ([50.0, 2.5000, 0.0000], [50.0, 1.8634, 0.5757], 1.0000),
+        ([50.0, 2.5000, 0.0000], [50.0, 3.2592, 0.3350], 1.0000),
+        ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+        ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+        ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+        ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+        ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+        ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+        ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+        ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+        ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+
+    fn lab([l, a, b]: [f32; 3]) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn delta_e2000_matches_sharma() {
+        for &(c1, c2, expected) in SHARMA.iter() {
+            let difference = delta_e2000(&lab(c1), &lab(c2));
+            assert!((difference - expected).abs() < 1e-3, "{:?} {:?}: {} instead of {}", c1, c2, difference, expected);
+            // the difference is symmetric
+            assert!((delta_e2000(&lab(c2), &lab(c1)) - difference).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn srgb_white_is_lab_white() {
+        let white = Lab::from_srgb(&Rgb([255, 255, 255]));
+        assert!((white.l - 100.0).abs() < 0.01 && white.a.abs() < 0.01 && white.b.abs() < 0.01);
+        assert_eq!(delta_e76(&white, &white), 0.0);
+    }
+}
@@ -0,0 +1,143 @@
+//! Color corrections of the skin: removing backdrop spill and balancing the lamp's color.
+
+use image::{
+    ImageBuffer,
+    Rgb,
+};
+
+use crate::{
+    color::{
+        linear_to_srgb16,
+        srgb16_to_linear,
+        srgb_to_linear,
+    },
+    crop::CropRect,
+    error::ScanError,
+    heightmap::distance_transform,
+    input::{
+        MaskImage,
+        Rgb16Image,
+    },
+    morphology::Bitmap,
+};
+
+/// how far into the fish (in pixels at 1024) despill reaches, light bounced off the
+/// backdrop tints the outermost part of the paper
+const SPILL_BAND: f32 = 6.0;
+/// keeps a badly chosen reference from turning the fish into a neon sign
+const MAX_GAIN: f32 = 4.0;
+
+/// What is known to be neutral gray in the photo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhiteReference {
+    /// the keyed backdrop, e.g. gray cardboard
+    Backdrop,
+    /// a gray card in this part of the photo
+    Region(CropRect),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WhiteBalance {
+    pub reference: WhiteReference,
+    /// the gray level (sRGB, 0-255) the reference should end up at, e.g. 118 for an 18% gray card.
+    /// `None` only corrects the color and keeps the exposure.
+    pub target: Option<u8>,
+}
+
+/// Removes the hue of `key` (the backdrop color, 8 bit sRGB) from the outline of the fish:
+/// from semi-transparent pixels completely, fading out over a narrow band inside.
+/// A neutral backdrop has no hue to remove.
+pub fn despill(img: &Rgb16Image, mask: &MaskImage, key: &Rgb<u8>, scale: f32) -> Rgb16Image {
+    let key = [0, 1, 2].map(|c| srgb_to_linear(key[c] as f32 / 255.0));
+    let key_mean = (key[0] + key[1] + key[2]) / 3.0;
+    let hue = [key[0] - key_mean, key[1] - key_mean, key[2] - key_mean];
+    let length = (hue[0] * hue[0] + hue[1] * hue[1] + hue[2] * hue[2]).sqrt();
+    if length < 0.01 * key_mean.max(0.01) {
+        return img.clone();
+    }
+    let hue = hue.map(|c| c / length);
+
+    let fish = Bitmap::from_mask(mask, u16::MAX / 2);
+    let inside = distance_transform(&fish);
+    let band = SPILL_BAND * scale;
+    let (width, height) = img.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let p = img.get_pixel(x, y);
+        let alpha = mask.get_pixel(x, y)[0] as f32 / u16::MAX as f32;
+        let weight = (1.0 - alpha).max(1.0 - inside[(y * width + x) as usize] / band).max(0.0);
+        if weight <= 0.0 {
+            return *p;
+        }
+        let rgb = [srgb16_to_linear(p[0]), srgb16_to_linear(p[1]), srgb16_to_linear(p[2])];
+        let mean = (rgb[0] + rgb[1] + rgb[2]) / 3.0;
+        // how far the pixel leans towards the backdrop's hue. Moving against the hue
+        // only changes the hue, not the mean brightness
+        let spill = (0..3).map(|c| (rgb[c] - mean) * hue[c]).sum::<f32>().max(0.0) * weight;
+        Rgb([0, 1, 2].map(|c| linear_to_srgb16(rgb[c] - spill * hue[c])))
+    })
+}
+
+/// Per channel gains (linear light) that turn `reference` (its average color) neutral gray.
+pub fn white_balance_gains(photo: &Rgb16Image, balance: &WhiteBalance, backdrop: Option<&Rgb<u8>>) -> Result<[f32; 3], ScanError> {
+    let reference = match balance.reference {
+        WhiteReference::Backdrop => {
+            let backdrop = backdrop.ok_or_else(|| {
+                ScanError::InvalidOption("white balance against the backdrop needs a keyed photo".to_string())
+            })?;
+            [0, 1, 2].map(|c| srgb_to_linear(backdrop[c] as f32 / 255.0))
+        }
+        WhiteReference::Region(rect) => {
+            let (width, height) = photo.dimensions();
+            if rect.left < 0 || rect.top < 0 || rect.width == 0 || rect.height == 0
+                || (rect.left as u32).checked_add(rect.width).is_none_or(|right| right > width)
+                || (rect.top as u32).checked_add(rect.height).is_none_or(|bottom| bottom > height) {
+                return Err(ScanError::InvalidOption(format!(
+                    "gray card region {},{},{},{} is not inside the {}x{} photo",
+                    rect.left, rect.top, rect.width, rect.height, width, height,
+                )));
+            }
+            let mut sum = [0f64; 3];
+            for y in rect.top as u32..rect.top as u32 + rect.height {
+                for x in rect.left as u32..rect.left as u32 + rect.width {
+                    let p = photo.get_pixel(x, y);
+                    for (c, s) in sum.iter_mut().enumerate() {
+                        *s += srgb16_to_linear(p[c]) as f64;
+                    }
+                }
+            }
+            let n = (rect.width * rect.height) as f64;
+            sum.map(|s| (s / n) as f32)
+        }
+    };
+    let gray = match balance.target {
+        Some(level) => srgb_to_linear(level as f32 / 255.0),
+        None => (reference[0] + reference[1] + reference[2]) / 3.0,
+    };
+    Ok(reference.map(|r| (gray / r.max(f32::EPSILON)).clamp(1.0 / MAX_GAIN, MAX_GAIN)))
+}
+
+pub fn apply_gains(img: &Rgb16Image, gains: [f32; 3]) -> Rgb16Image {
+    let (width, height) = img.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let p = img.get_pixel(x, y);
+        Rgb([0, 1, 2].map(|c| linear_to_srgb16(srgb16_to_linear(p[c]) * gains[c])))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gray_card_outside_the_photo() {
+        let photo = Rgb16Image::from_pixel(8, 8, Rgb([30000; 3]));
+        let region = |left, top, width, height| WhiteBalance {
+            reference: WhiteReference::Region(CropRect { left, top, width, height }),
+            target: None,
+        };
+        assert!(white_balance_gains(&photo, &region(2, 2, 4, 4), None).is_ok());
+        for balance in [region(2, 2, 8, 4), region(2, 2, u32::MAX, 4), region(2, 2, 4, u32::MAX - 1), region(-1, 0, 4, 4)] {
+            assert!(matches!(white_balance_gains(&photo, &balance, None), Err(ScanError::InvalidOption(_))));
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use image::{
+    ImageBuffer,
+    Pixel,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::morphology::BoundingBox;
+
+/// A region of the photo, may reach beyond its borders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropRect {
+    /// The smallest rect with the given aspect ratio (width / height) that is centered on `bbox`
+    /// and leaves `padding` percent of the fish's longer extent free on every side.
+    pub fn around(bbox: &BoundingBox, aspect: f32, padding: f32) -> CropRect {
+        let pad = bbox.width().max(bbox.height()) as f32 * padding / 100.0;
+        let padded_width = bbox.width() as f32 + 2.0 * pad;
+        let padded_height = bbox.height() as f32 + 2.0 * pad;
+        let width = padded_width.max(padded_height * aspect).ceil();
+        let height = (width / aspect).ceil();
+        let center_x = (bbox.left + bbox.right) as f32 / 2.0;
+        let center_y = (bbox.top + bbox.bottom) as f32 / 2.0;
+        CropRect {
+            left: (center_x - width / 2.0).round() as i32,
+            top: (center_y - height / 2.0).round() as i32,
+            width: width as u32,
+            height: height as u32,
+        }
+    }
+}
+
+/// Copies `rect` out of `img`, pixels outside of `img` become `fill`.
+pub fn crop<P: Pixel + 'static>(img: &ImageBuffer<P, Vec<P::Subpixel>>, rect: &CropRect, fill: P) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = img.dimensions();
+    ImageBuffer::from_fn(rect.width, rect.height, |x, y| {
+        let origx = x as i32 + rect.left;
+        let origy = y as i32 + rect.top;
+        if origx < 0 || origy < 0 || origx >= width as i32 || origy >= height as i32 {
+            fill
+        } else {
+            *img.get_pixel(origx as u32, origy as u32)
+        }
+    })
+}
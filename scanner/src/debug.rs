@@ -0,0 +1,175 @@
+//! Dumps the intermediate images of a scan, for finding out which step went wrong.
+
+use image::{
+    imageops,
+    imageops::FilterType,
+    DynamicImage,
+    GenericImageView,
+    ImageBuffer,
+    Rgb,
+    RgbImage,
+};
+
+use crate::error::ScanError;
+
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// the contact sheet shows every stage in a square of this size
+const TILE_SIZE: u32 = 320;
+const COLUMNS: u32 = 4;
+/// label font: 5x7 pixel glyphs, drawn twice the size
+const FONT_SCALE: u32 = 2;
+const LABEL_HEIGHT: u32 = 7 * FONT_SCALE + 10;
+const SHEET_BACKGROUND: Rgb<u8> = Rgb([48, 48, 48]);
+const CHECKER_DARK: Rgb<u8> = Rgb([96, 96, 96]);
+const CHECKER_LIGHT: Rgb<u8> = Rgb([160, 160, 160]);
+const LABEL_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// Collects the stages of one scan. Does nothing if it has no directory.
+pub struct DebugDump {
+    dir: Option<PathBuf>,
+    stages: Vec<(String, DynamicImage)>,
+}
+
+impl DebugDump {
+    pub fn new(dir: Option<&Path>) -> DebugDump {
+        DebugDump {
+            dir: dir.map(Path::to_path_buf),
+            stages: Vec::new(),
+        }
+    }
+
+    /// A dump in the subdirectory `name`, disabled if this one is.
+    pub fn nested(&self, name: &str) -> DebugDump {
+        DebugDump {
+            dir: self.dir.as_ref().map(|dir| dir.join(name)),
+            stages: Vec::new(),
+        }
+    }
+
+    /// Records a stage. `image` is only called if the dump is enabled, so copying
+    /// buffers costs nothing in normal runs.
+    pub fn add<F: FnOnce() -> DynamicImage>(&mut self, label: &str, image: F) {
+        if self.dir.is_some() {
+            self.stages.push((label.to_string(), image()));
+        }
+    }
+
+    /// Writes every stage as `NN-label.png` plus `contact-sheet.png` with all of them.
+    pub fn write(&self) -> Result<(), ScanError> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(dir)?;
+        for (i, (label, image)) in self.stages.iter().enumerate() {
+            let name = format!("{:02}-{}.png", i + 1, label.replace(' ', "-"));
+            image.save(dir.join(name))?;
+        }
+        contact_sheet(&self.stages).save(dir.join("contact-sheet.png"))?;
+        Ok(())
+    }
+}
+
+fn contact_sheet(stages: &[(String, DynamicImage)]) -> RgbImage {
+    let rows = (stages.len() as u32).div_ceil(COLUMNS);
+    let mut sheet = ImageBuffer::from_pixel(COLUMNS * TILE_SIZE, rows.max(1) * (TILE_SIZE + LABEL_HEIGHT), SHEET_BACKGROUND);
+    for (i, (label, image)) in stages.iter().enumerate() {
+        let left = i as u32 % COLUMNS * TILE_SIZE;
+        let top = i as u32 / COLUMNS * (TILE_SIZE + LABEL_HEIGHT);
+        draw_text(&mut sheet, left + 5, top + 5, &format!("{:02} {}", i + 1, label));
+
+        let (width, height) = (image.width().max(1), image.height().max(1));
+        let fit = (TILE_SIZE - 8) as f32 / width.max(height) as f32;
+        let thumb_width = ((width as f32 * fit) as u32).max(1);
+        let thumb_height = ((height as f32 * fit) as u32).max(1);
+        let thumb = image.resize_exact(thumb_width, thumb_height, FilterType::Triangle).into_rgba8();
+        // transparent parts over a checkerboard, so an empty mask doesn't look like a black fish
+        let thumb = ImageBuffer::from_fn(thumb_width, thumb_height, |x, y| {
+            let p = thumb.get_pixel(x, y);
+            let checker = if (x / 8 + y / 8) % 2 == 0 { CHECKER_DARK } else { CHECKER_LIGHT };
+            let a = p[3] as u32;
+            let mix = |c: usize| ((p[c] as u32 * a + checker[c] as u32 * (255 - a)) / 255) as u8;
+            Rgb([mix(0), mix(1), mix(2)])
+        });
+        let x = left + (TILE_SIZE - thumb_width) / 2;
+        let y = top + LABEL_HEIGHT + (TILE_SIZE - thumb_height) / 2;
+        imageops::replace(&mut sheet, &thumb, x, y);
+    }
+    sheet
+}
+
+fn draw_text(img: &mut RgbImage, left: u32, top: u32, text: &str) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_left = left + i as u32 * 6 * FONT_SCALE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..5 {
+                if bits & (0b10000 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..FONT_SCALE {
+                    for dx in 0..FONT_SCALE {
+                        let x = glyph_left + col * FONT_SCALE + dx;
+                        let y = top + row as u32 * FONT_SCALE + dy;
+                        if x < img.width() && y < img.height() {
+                            img.put_pixel(x, y, LABEL_COLOR);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// rows of a 5x7 glyph, top to bottom. Lowercase is drawn as uppercase, unknown characters blank.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        '/' => [0b00001, 0b00010, 0b00010, 0b00100, 0b01000, 0b01000, 0b10000],
+        _ => [0; 7],
+    }
+}
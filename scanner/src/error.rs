@@ -0,0 +1,86 @@
+use image::ImageError;
+
+use crate::quality::{
+    QualityReport,
+    Severity,
+};
+
+use std::{
+    fmt,
+    io,
+};
+
+/// Everything that can go wrong while turning a photo into a fish skin.
+///
+/// There is no error for pixel formats: every format `image` decodes is converted, see
+/// `input::Photo`, so a photo the scanner can't use is a `Decode` error. A fish that reaches
+/// the edge of the photo is one of the quality checks, match it with
+/// `ScanError::Rejected(report) if report.rejected_by(Check::Border)`.
+#[derive(Debug)]
+pub enum ScanError {
+    /// the photo could not be read from (or the results written to) disk
+    Io(io::Error),
+    /// the file exists but could not be decoded as an image
+    Decode(ImageError),
+    /// a camera raw file is broken or uses a variant the scanner can't decode, see `raw`
+    Raw(String),
+    /// no pixel differs enough from the background color
+    EmptyMask,
+    /// a check of `ScanOptions::quality` failed, e.g. the fish reaches the edge of the photo
+    Rejected(Box<QualityReport>),
+    /// fewer than the four registration marks of a coloring page were found, see `template`
+    MarksNotFound(usize),
+    /// no sheet of paper stands out from the table, see `perspective::find_paper`
+    PaperNotFound,
+    /// a `ScanOptions` value is out of range
+    InvalidOption(String),
+    /// a fish manifest could not be read or written
+    Manifest(String),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::Io(err) => write!(f, "i/o error: {}", err),
+            ScanError::Decode(err) => write!(f, "could not decode image: {}", err),
+            ScanError::Raw(message) => write!(f, "could not decode raw file: {}", message),
+            ScanError::EmptyMask => write!(f, "no fish found, every pixel matches the background color"),
+            ScanError::Rejected(report) => {
+                let reasons: Vec<&str> = report.findings.iter()
+                    .filter(|finding| finding.severity == Severity::Reject)
+                    .map(|finding| finding.message.as_str())
+                    .collect();
+                write!(f, "rejected: {}", reasons.join(", "))
+            }
+            ScanError::PaperNotFound => write!(f, "no paper found, it has to be brighter than the table"),
+            ScanError::MarksNotFound(found) => write!(f, "found {} of the 4 registration marks of the coloring page", found),
+            ScanError::InvalidOption(message) => write!(f, "invalid option: {}", message),
+            ScanError::Manifest(message) => write!(f, "invalid fish manifest: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScanError::Io(err) => Some(err),
+            ScanError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScanError {
+    fn from(err: io::Error) -> Self {
+        ScanError::Io(err)
+    }
+}
+
+impl From<ImageError> for ScanError {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::IoError(err) => ScanError::Io(err),
+            err => ScanError::Decode(err),
+        }
+    }
+}
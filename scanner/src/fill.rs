@@ -0,0 +1,239 @@
+//! Spreading the fish's edge colors into its transparent surroundings.
+//!
+//! `sea` samples the colors texture linearly, so whatever color the transparent pixels carry
+//! bleeds into the outline, and smaller mip levels mix in even more of it. Left alone that's
+//! the backdrop, which draws a dark fringe around every fish.
+
+use image::{
+    ImageBuffer,
+    Rgb,
+};
+
+use crate::input::{
+    MaskImage,
+    Rgb16Image,
+};
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeFill {
+    /// leave the backdrop color, like the original scanner
+    None,
+    /// every transparent pixel takes the color of the nearest opaque ones
+    Flood,
+    /// opaque colors are averaged down an image pyramid and pushed back up,
+    /// which gives smooth gradients far from the fish
+    PullPush,
+}
+
+impl FromStr for EdgeFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(EdgeFill::None),
+            "flood" => Ok(EdgeFill::Flood),
+            "pull-push" => Ok(EdgeFill::PullPush),
+            _ => Err(format!("unknown edge fill: {} (none, flood, pull-push)", s)),
+        }
+    }
+}
+
+/// Replaces the colors of all pixels that aren't fully opaque in `mask` with colors from the fish.
+/// Semi-transparent edge pixels are replaced too, their color is mixed with the backdrop.
+pub fn fill_edges(img: &Rgb16Image, mask: &MaskImage, mode: EdgeFill) -> Rgb16Image {
+    // a graded mask might never be fully opaque
+    let max = mask.pixels().map(|p| p[0]).max().unwrap_or(0);
+    let threshold = (max as f32 * 0.99) as u16;
+    if mode == EdgeFill::None || max == 0 {
+        return img.clone();
+    }
+    let known: Vec<bool> = mask.pixels().map(|p| p[0] > 0 && p[0] >= threshold).collect();
+    let colors: Vec<[f32; 3]> = img.pixels().map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]).collect();
+    let (width, height) = img.dimensions();
+    let filled = match mode {
+        EdgeFill::Flood => flood(&colors, &known, width, height),
+        _ => pull_push(&colors, &known, width, height),
+    };
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let c = filled[(y * width + x) as usize];
+        Rgb([c[0].round() as u16, c[1].round() as u16, c[2].round() as u16])
+    })
+}
+
+/// grows the known region ring by ring, each new pixel averages its known neighbours
+fn flood(colors: &[[f32; 3]], known: &[bool], width: u32, height: u32) -> Vec<[f32; 3]> {
+    let (width, height) = (width as i64, height as i64);
+    let mut colors = colors.to_vec();
+    let mut known = known.to_vec();
+    let mut queued = known.clone();
+    let neighbours = |i: usize| {
+        let (x, y) = (i as i64 % width, i as i64 / width);
+        (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(move |&(nx, ny)| (nx, ny) != (x, y) && nx >= 0 && ny >= 0 && nx < width && ny < height)
+            .map(move |(nx, ny)| (ny * width + nx) as usize)
+    };
+
+    let mut ring = Vec::new();
+    for (i, _) in known.iter().enumerate().filter(|(_, &k)| k) {
+        for n in neighbours(i) {
+            if !queued[n] {
+                queued[n] = true;
+                ring.push(n);
+            }
+        }
+    }
+    while !ring.is_empty() {
+        let mut next = Vec::new();
+        let mut ring_colors = Vec::with_capacity(ring.len());
+        for &i in &ring {
+            let mut sum = [0.0; 3];
+            let mut count = 0.0;
+            for n in neighbours(i).filter(|&n| known[n]) {
+                for c in 0..3 {
+                    sum[c] += colors[n][c];
+                }
+                count += 1.0;
+            }
+            ring_colors.push([sum[0] / count, sum[1] / count, sum[2] / count]);
+        }
+        // only mark the ring as known once it's complete, so the order inside a ring doesn't matter
+        for (&i, color) in ring.iter().zip(ring_colors) {
+            colors[i] = color;
+            known[i] = true;
+        }
+        for &i in &ring {
+            for n in neighbours(i) {
+                if !queued[n] {
+                    queued[n] = true;
+                    next.push(n);
+                }
+            }
+        }
+        ring = next;
+    }
+    colors
+}
+
+/// Pull-push interpolation (Gortler et al., "The Lumigraph", 1996).
+fn pull_push(colors: &[[f32; 3]], known: &[bool], width: u32, height: u32) -> Vec<[f32; 3]> {
+    // level 0 is the image, each further level half the size. Colors are stored premultiplied
+    // by their weight, weights saturate at 1.
+    let mut levels = vec![(
+        width as usize,
+        height as usize,
+        colors.iter().zip(known).map(|(c, &k)| if k { [c[0], c[1], c[2], 1.0] } else { [0.0; 4] }).collect::<Vec<[f32; 4]>>(),
+    )];
+    while levels.last().is_some_and(|(w, h, _)| *w > 1 || *h > 1) {
+        let (w, h, fine) = levels.last().unwrap();
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+        let mut coarse = vec![[0.0f32; 4]; cw * ch];
+        for y in 0..*h {
+            for x in 0..*w {
+                let p = &fine[y * w + x];
+                let q = &mut coarse[y / 2 * cw + x / 2];
+                for c in 0..4 {
+                    q[c] += p[c];
+                }
+            }
+        }
+        for q in &mut coarse {
+            if q[3] > 1.0 {
+                let scale = 1.0 / q[3];
+                for v in q.iter_mut() {
+                    *v *= scale;
+                }
+            }
+        }
+        levels.push((cw, ch, coarse));
+    }
+
+    // push: fill the holes of every level from the (bilinearly upsampled) level below
+    for i in (0..levels.len() - 1).rev() {
+        let (finer, coarser) = levels.split_at_mut(i + 1);
+        let (w, h, fine) = &mut finer[i];
+        let (cw, ch, coarse) = (coarser[0].0, coarser[0].1, &coarser[0].2);
+        let sample = |x: f32, y: f32| {
+            let x = x.max(0.0).min((cw - 1) as f32);
+            let y = y.max(0.0).min((ch - 1) as f32);
+            let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+            let (x1, y1) = ((x0 + 1).min(cw - 1), (y0 + 1).min(ch - 1));
+            let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+            let mut out = [0.0; 4];
+            for (c, value) in out.iter_mut().enumerate() {
+                *value = coarse[y0 * cw + x0][c] * (1.0 - fx) * (1.0 - fy)
+                    + coarse[y0 * cw + x1][c] * fx * (1.0 - fy)
+                    + coarse[y1 * cw + x0][c] * (1.0 - fx) * fy
+                    + coarse[y1 * cw + x1][c] * fx * fy;
+            }
+            out
+        };
+        for y in 0..*h {
+            for x in 0..*w {
+                let p = &mut fine[y * *w + x];
+                if p[3] >= 1.0 {
+                    continue;
+                }
+                let q = sample((x as f32 - 0.5) / 2.0, (y as f32 - 0.5) / 2.0);
+                // normalize the coarse sample to weight 1, then blend it in where the fine level has no weight
+                let q_weight = q[3].max(f32::EPSILON);
+                let missing = 1.0 - p[3];
+                for c in 0..3 {
+                    p[c] += q[c] / q_weight * missing;
+                }
+                p[3] = 1.0;
+            }
+        }
+    }
+
+    levels[0].2.iter().zip(colors).zip(known).map(|((p, original), &k)| {
+        if k {
+            *original
+        } else {
+            [p[0], p[1], p[2]]
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pull_push_spreads_a_single_color() {
+        let (width, height) = (7, 5);
+        let known: Vec<bool> = (0..width * height).map(|i| i % width < 2).collect();
+        let colors: Vec<[f32; 3]> = known.iter().map(|&k| if k { [0.2, 0.4, 0.6] } else { [0.0; 3] }).collect();
+        for color in pull_push(&colors, &known, width as u32, height as u32) {
+            for (got, expected) in color.iter().zip(&[0.2, 0.4, 0.6]) {
+                assert!((got - expected).abs() < 1e-5, "{:?}", color);
+            }
+        }
+    }
+
+    #[test]
+    fn pull_push_blends_between_known_pixels() {
+        // red on the left edge, blue on the right edge, unknown in between
+        let (width, height) = (8, 4);
+        let known: Vec<bool> = (0..width * height).map(|i| i % width == 0 || i % width == width - 1).collect();
+        let colors: Vec<[f32; 3]> = (0..width * height)
+            .map(|i| match i % width {
+                0 => [1.0, 0.0, 0.0],
+                x if x == width - 1 => [0.0, 0.0, 1.0],
+                _ => [0.5, 0.5, 0.5],
+            })
+            .collect();
+        let filled = pull_push(&colors, &known, width as u32, height as u32);
+        for y in 0..height {
+            let row = &filled[y * width..(y + 1) * width];
+            assert_eq!(row[0], [1.0, 0.0, 0.0]);
+            assert_eq!(row[width - 1], [0.0, 0.0, 1.0]);
+            for &[r, g, b] in &row[1..width - 1] {
+                assert!(g.abs() < 1e-5 && (r + b - 1.0).abs() < 1e-4, "{} {} {}", r, g, b);
+            }
+            // redder towards the red edge
+            assert!(row[1][0] > row[width - 2][0]);
+        }
+    }
+}
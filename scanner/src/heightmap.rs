@@ -0,0 +1,236 @@
+//! How much the fish bulges out of its texture plane, the basis of the normal map.
+
+use image::{
+    imageops,
+    imageops::FilterType,
+    DynamicImage,
+    ImageBuffer,
+    Luma,
+};
+
+use crate::{
+    debug::DebugDump,
+    input::MaskImage,
+    morphology::Bitmap,
+};
+
+use std::str::FromStr;
+
+/// Cross-section of the body from the outline (0) to the thickest spot (1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// a ridge, height grows linearly with the distance to the outline
+    Linear,
+    /// rises steeply at the outline and flattens out towards the middle
+    Sqrt,
+    /// a round belly, quarter circle cross-section
+    Elliptical,
+}
+
+impl Profile {
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Profile::Linear => t,
+            Profile::Sqrt => t.sqrt(),
+            Profile::Elliptical => (1.0 - (1.0 - t) * (1.0 - t)).sqrt(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeightModel {
+    /// the original scanner's: a heavily blurred, downsampled mask. Gives a dome that
+    /// doesn't care much about the shape, thin fins bulge about as much as the belly.
+    Blur,
+    /// Height follows the distance to the outline, relative to the thickest spot of the fish,
+    /// so thin fins stay flat and the body gets a round cross-section.
+    Distance(Profile),
+}
+
+impl FromStr for HeightModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blur" => Ok(HeightModel::Blur),
+            "linear" => Ok(HeightModel::Distance(Profile::Linear)),
+            "sqrt" => Ok(HeightModel::Distance(Profile::Sqrt)),
+            "elliptical" => Ok(HeightModel::Distance(Profile::Elliptical)),
+            _ => Err(format!("unknown height model: {} (blur, linear, sqrt, elliptical)", s)),
+        }
+    }
+}
+
+/// thickness of the body relative to its height
+const THICKNESS: f32 = 0.5;
+
+/// Heightmap of the same size as `mask`. `scale` is the output size relative to 1024.
+pub fn heightmap(mask: &MaskImage, model: HeightModel, scale: f32, debug: &mut DebugDump) -> MaskImage {
+    match model {
+        HeightModel::Blur => blur_heightmap(mask, debug),
+        HeightModel::Distance(profile) => distance_heightmap(mask, profile, scale, debug),
+    }
+}
+
+// crop mask to rect
+// - scale to 1/8
+//   - blur
+//   - unscale
+fn blur_heightmap(mask: &MaskImage, debug: &mut DebugDump) -> MaskImage {
+    let (width, height) = mask.dimensions();
+    let downsample_width = (width / 8).max(1);
+    let downsample_height = (height / 8).max(1);
+    let blur_radius = (downsample_width.max(downsample_height) / 8).max(1);
+    let downsampled_mask = imageops::resize(mask, downsample_width, downsample_height, FilterType::CatmullRom);
+    let downsampled_mask_with_border = ImageBuffer::from_fn(downsample_width + blur_radius * 2, downsample_height + blur_radius * 2, |x, y| {
+        if x >= blur_radius && x < blur_radius + downsample_width && y >= blur_radius && y < downsample_height + blur_radius {
+            *downsampled_mask.get_pixel(x - blur_radius, y - blur_radius)
+        } else {
+            Luma([0])
+        }
+    });
+    let blurred_downsampled_mask = imageops::blur(&downsampled_mask_with_border, blur_radius as f32);
+    let cropped_back = ImageBuffer::from_fn(downsample_width, downsample_height, |x, y| {
+        *blurred_downsampled_mask.get_pixel(x + blur_radius, y + blur_radius)
+    });
+    debug.add("downsampled heightmap", || DynamicImage::ImageLuma16(cropped_back.clone()));
+    imageops::resize(&cropped_back, width, height, FilterType::CatmullRom)
+}
+
+fn distance_heightmap(mask: &MaskImage, profile: Profile, scale: f32, debug: &mut DebugDump) -> MaskImage {
+    let fish = Bitmap::from_mask(mask, u16::MAX / 2);
+    let distances = distance_transform(&fish);
+    let max = distances.iter().cloned().fold(0.0, f32::max).max(1.0);
+    debug.add("distance to outline", || {
+        DynamicImage::ImageLuma16(ImageBuffer::from_fn(fish.width, fish.height, |x, y| {
+            Luma([(distances[(y * fish.width + x) as usize] / max * u16::MAX as f32) as u16])
+        }))
+    });
+    // the normals treat a height step of 1 as 200 pixels (at 1024), and a fish seen from
+    // the side is only about half as thick as it is tall
+    let top = (THICKNESS * max / (200.0 * scale)).min(1.0);
+    let heightmap = ImageBuffer::from_fn(fish.width, fish.height, |x, y| {
+        let t = distances[(y * fish.width + x) as usize] / max;
+        Luma([(profile.apply(t) * top * u16::MAX as f32) as u16])
+    });
+    // the distance transform has a crease along the middle of the body, smooth it out
+    imageops::blur(&heightmap, 2.0 * scale)
+}
+
+/// Euclidean distance of every set pixel to the nearest unset one (0 for unset pixels).
+/// Pixels outside the bitmap count as unset.
+///
+/// Separable exact algorithm by Felzenszwalb and Huttenlocher, "Distance Transforms of
+/// Sampled Functions" (2012).
+pub fn distance_transform(bitmap: &Bitmap) -> Vec<f32> {
+    let (width, height) = (bitmap.width as usize, bitmap.height as usize);
+    // squared distances, "infinite" inside the fish. The padding of one unset pixel on every
+    // side makes the border count as background.
+    let far = ((width + height) * (width + height)) as f32;
+    let mut grid: Vec<f32> = bitmap.data.iter().map(|&set| if set { far } else { 0.0 }).collect();
+
+    let mut line = Vec::new();
+    let mut out = Vec::new();
+    for x in 0..width {
+        line.clear();
+        line.push(0.0);
+        line.extend((0..height).map(|y| grid[y * width + x]));
+        line.push(0.0);
+        distance_transform_1d(&line, &mut out);
+        for y in 0..height {
+            grid[y * width + x] = out[y + 1];
+        }
+    }
+    for y in 0..height {
+        line.clear();
+        line.push(0.0);
+        line.extend_from_slice(&grid[y * width..(y + 1) * width]);
+        line.push(0.0);
+        distance_transform_1d(&line, &mut out);
+        grid[y * width..(y + 1) * width].copy_from_slice(&out[1..width + 1]);
+    }
+    grid.iter().map(|d| d.sqrt()).collect()
+}
+
+/// lower envelope of the parabolas rooted at every sample of `f`
+fn distance_transform_1d(f: &[f32], out: &mut Vec<f32>) {
+    let n = f.len();
+    out.clear();
+    out.resize(n, 0.0);
+    let mut v = vec![0usize; n];
+    let mut z = vec![0f32; n + 1];
+    let mut k = 0;
+    z[0] = f32::NEG_INFINITY;
+    z[1] = f32::INFINITY;
+    let intersection = |q: usize, p: usize| {
+        ((f[q] + (q * q) as f32) - (f[p] + (p * p) as f32)) / (2.0 * q as f32 - 2.0 * p as f32)
+    };
+    for q in 1..n {
+        let mut s = intersection(q, v[k]);
+        while s <= z[k] {
+            k -= 1;
+            s = intersection(q, v[k]);
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f32::INFINITY;
+    }
+    k = 0;
+    for (q, value) in out.iter_mut().enumerate() {
+        while z[k + 1] < q as f32 {
+            k += 1;
+        }
+        let d = q as f32 - v[k] as f32;
+        *value = d * d + f[v[k]];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// distance to the nearest unset pixel, including the ring of pixels around the bitmap
+    fn brute_force(bitmap: &Bitmap) -> Vec<f32> {
+        let (width, height) = (bitmap.width as i32, bitmap.height as i32);
+        let unset = |x: i32, y: i32| x < 0 || y < 0 || x >= width || y >= height || !bitmap.get(x as u32, y as u32);
+        let mut distances = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let mut nearest = f32::INFINITY;
+                for v in -1..=height {
+                    for u in -1..=width {
+                        if unset(u, v) {
+                            nearest = nearest.min((((u - x).pow(2) + (v - y).pow(2)) as f32).sqrt());
+                        }
+                    }
+                }
+                distances.push(if unset(x, y) { 0.0 } else { nearest });
+            }
+        }
+        distances
+    }
+
+    #[test]
+    fn distances_to_the_border() {
+        let mut bitmap = Bitmap::new(5, 5);
+        bitmap.data.iter_mut().for_each(|set| *set = true);
+        let distances = distance_transform(&bitmap);
+        assert_eq!(distances[0], 1.0);
+        assert_eq!(distances[2 * 5 + 1], 2.0);
+        assert_eq!(distances[2 * 5 + 2], 3.0);
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut bitmap = Bitmap::new(9, 6);
+        for y in 0..6 {
+            for x in 0..9 {
+                bitmap.set(x, y, (x * 7 + y * 3) % 5 != 0);
+            }
+        }
+        for (got, expected) in distance_transform(&bitmap).iter().zip(brute_force(&bitmap)) {
+            assert!((got - expected).abs() < 1e-5, "{} instead of {}", got, expected);
+        }
+    }
+}
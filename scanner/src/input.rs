@@ -0,0 +1,99 @@
+use image::{
+    DynamicImage,
+    ImageBuffer,
+    ImageFormat,
+    Luma,
+    Rgb,
+};
+
+use crate::{
+    error::ScanError,
+    metadata::{
+        exif_orientation,
+        icc_profile,
+        orient,
+        to_srgb,
+    },
+    raw,
+};
+
+use std::{
+    fs,
+    path::Path,
+};
+
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+pub type MaskImage = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+/// A photo normalized to 16 bit rgb, whatever pixel format it came in.
+///
+/// 8 bit sources are expanded (255 -> 65535), so downstream steps don't need to care.
+/// 16 bit sources keep their precision, which the heightmap benefits from.
+pub struct Photo {
+    pub rgb: Rgb16Image,
+    /// the source's alpha channel, if it had one that isn't fully opaque
+    pub alpha: Option<MaskImage>,
+}
+
+impl Photo {
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.rgb.dimensions()
+    }
+}
+
+impl From<DynamicImage> for Photo {
+    fn from(image: DynamicImage) -> Self {
+        if !image.color().has_alpha() {
+            return Photo {
+                rgb: image.into_rgb16(),
+                alpha: None,
+            };
+        }
+
+        let rgba = image.into_rgba16();
+        let (width, height) = rgba.dimensions();
+        let alpha = ImageBuffer::from_fn(width, height, |x, y| Luma([rgba.get_pixel(x, y)[3]]));
+        // phones like to save rgba pngs that are opaque everywhere,
+        // those still need background keying
+        let opaque = alpha.pixels().all(|a| a[0] == u16::MAX);
+        let rgb = ImageBuffer::from_fn(width, height, |x, y| {
+            let p = rgba.get_pixel(x, y);
+            Rgb([p[0], p[1], p[2]])
+        });
+        Photo {
+            rgb,
+            alpha: if opaque { None } else { Some(alpha) },
+        }
+    }
+}
+
+/// Opens any image `image` can decode, and camera raw files (see `raw`).
+/// The image is turned upright according to its EXIF orientation and an embedded
+/// color profile is converted to sRGB.
+pub fn open_image<P: AsRef<Path>>(path: P) -> Result<DynamicImage, ScanError> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    let image = if raw::is_raw_file(path) {
+        raw::decode(&data)?
+    } else {
+        let image = image::load_from_memory_with_format(&data, ImageFormat::from_path(path)?)?;
+        match icc_profile(&data) {
+            Some(profile) => to_srgb(image, &profile),
+            None => image,
+        }
+    };
+    Ok(match exif_orientation(&data) {
+        Some(orientation) => orient(image, orientation),
+        None => image,
+    })
+}
+
+/// 16 bit channel value to 8 bit
+pub fn to_u8(c: u16) -> u8 {
+    ((c as u32 * 255 + 32767) / 65535) as u8
+}
+
+/// 8 bit channel value to 16 bit, 255 maps to 65535
+pub fn to_u16(c: u8) -> u16 {
+    c as u16 * 257
+}
@@ -0,0 +1,153 @@
+use image::{
+    imageops,
+    ImageBuffer,
+    Luma,
+    Rgb,
+};
+
+use crate::{
+    color::{
+        delta_e76,
+        delta_e2000,
+        Lab,
+    },
+    input::{
+        MaskImage,
+        Rgb16Image,
+        to_u8,
+    },
+};
+
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+/// blur radius for photo and plate before comparing them
+const PLATE_DIFFERENCE_BLUR: f32 = 1.0;
+
+/// How the difference between a pixel and the background color is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMetric {
+    /// euclidean distance of the 8 bit rgb values, range [0, 441]
+    Rgb,
+    /// CIE76, euclidean distance in Lab, roughly [0, 100]
+    DeltaE76,
+    /// CIEDE2000, perceptually more uniform than CIE76, especially for saturated colors
+    DeltaE2000,
+    /// distance in the a*b* plane only, lightness is ignored.
+    /// Good for green screen style backdrops where shadows would otherwise count as fish.
+    Chroma,
+}
+
+impl KeyMetric {
+    /// key distance that works for typical photos
+    pub fn default_distance(self) -> f32 {
+        match self {
+            KeyMetric::Rgb => 20.0,
+            KeyMetric::DeltaE76 => 10.0,
+            KeyMetric::DeltaE2000 => 8.0,
+            KeyMetric::Chroma => 12.0,
+        }
+    }
+}
+
+impl FromStr for KeyMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb" => Ok(KeyMetric::Rgb),
+            "de76" => Ok(KeyMetric::DeltaE76),
+            "de2000" => Ok(KeyMetric::DeltaE2000),
+            "chroma" => Ok(KeyMetric::Chroma),
+            _ => Err(format!("unknown key metric: {} (rgb, de76, de2000, chroma)", s)),
+        }
+    }
+}
+
+impl fmt::Display for KeyMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            KeyMetric::Rgb => "rgb",
+            KeyMetric::DeltaE76 => "de76",
+            KeyMetric::DeltaE2000 => "de2000",
+            KeyMetric::Chroma => "chroma",
+        })
+    }
+}
+
+/// Measures color distances to one fixed background color.
+pub struct Keyer {
+    metric: KeyMetric,
+    color: Rgb<u8>,
+    lab: Lab,
+}
+
+impl Keyer {
+    pub fn new(metric: KeyMetric, color: Rgb<u8>) -> Keyer {
+        Keyer {
+            metric,
+            color,
+            lab: Lab::from_srgb(&color),
+        }
+    }
+
+    pub fn distance(&self, p: &Rgb<u8>) -> f32 {
+        match self.metric {
+            KeyMetric::Rgb => (rgb_distance_squared(p, &self.color) as f32).sqrt(),
+            KeyMetric::DeltaE76 => delta_e76(&self.lab, &Lab::from_srgb(p)),
+            KeyMetric::DeltaE2000 => delta_e2000(&self.lab, &Lab::from_srgb(p)),
+            KeyMetric::Chroma => {
+                let lab = Lab::from_srgb(p);
+                ((lab.a - self.lab.a).powi(2) + (lab.b - self.lab.b).powi(2)).sqrt()
+            }
+        }
+    }
+
+    /// Foreground mask: 0 for pixels closer than `tolerance`, full for pixels further away than
+    /// `tolerance + falloff`, and a smooth ramp in between.
+    pub fn mask(&self, img: &Rgb16Image, tolerance: f32, falloff: f32) -> MaskImage {
+        let (width, height) = img.dimensions();
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let p = img.get_pixel(x, y);
+            let d = self.distance(&Rgb([to_u8(p[0]), to_u8(p[1]), to_u8(p[2])]));
+            Luma([(coverage(d, tolerance, falloff) * u16::MAX as f32) as u16])
+        })
+    }
+}
+
+/// Foreground mask from comparing `img` with `plate`, a photo of the same backdrop without the fish,
+/// pixel by pixel. For textured or patterned backdrops that have no single color.
+/// Both are smoothed a little first, so camera noise and a tiny shake don't show up as fish.
+pub fn difference_mask(metric: KeyMetric, img: &Rgb16Image, plate: &Rgb16Image, tolerance: f32, falloff: f32) -> MaskImage {
+    let img = imageops::blur(img, PLATE_DIFFERENCE_BLUR);
+    let plate = imageops::blur(plate, PLATE_DIFFERENCE_BLUR);
+    let eight_bit = |p: &Rgb<u16>| Rgb([to_u8(p[0]), to_u8(p[1]), to_u8(p[2])]);
+    let (width, height) = img.dimensions();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let backdrop = eight_bit(plate.get_pixel(x, y));
+        let d = Keyer::new(metric, backdrop).distance(&eight_bit(img.get_pixel(x, y)));
+        Luma([(coverage(d, tolerance, falloff) * u16::MAX as f32) as u16])
+    })
+}
+
+/// how much of a pixel at distance `d` is foreground
+fn coverage(d: f32, tolerance: f32, falloff: f32) -> f32 {
+    if d < tolerance {
+        0.0
+    } else if d >= tolerance + falloff {
+        1.0
+    } else {
+        let t = (d - tolerance) / falloff;
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+pub fn rgb_distance_squared(c1: &Rgb<u8>, c2: &Rgb<u8>) -> u32 {
+    let mut sum:u32 = 0;
+    for i in 0..3 {
+        sum += (c1[i] as i32 - c2[i] as i32).pow(2) as u32
+    }
+    sum
+}
@@ -0,0 +1,517 @@
+use image::{
+    ImageBuffer,
+    Rgb,
+    Rgba,
+    Luma,
+    imageops,
+    imageops::{
+        FilterType,
+    },
+    DynamicImage,
+};
+
+use std::path::Path;
+
+pub mod background;
+pub mod batch;
+pub mod capture;
+pub mod color;
+pub mod correction;
+pub mod crop;
+pub mod debug;
+pub mod error;
+pub mod fill;
+pub mod heightmap;
+pub mod input;
+pub mod keying;
+pub mod lighting;
+pub mod linalg;
+pub mod ljpeg;
+pub mod manifest;
+pub mod metadata;
+pub mod morphology;
+pub mod normals;
+pub mod options;
+pub mod orientation;
+pub mod output;
+pub mod palette;
+pub mod perspective;
+pub mod quality;
+pub mod raw;
+pub mod styler;
+pub mod template;
+pub mod watch;
+
+pub use background::BackgroundKey;
+pub use error::ScanError;
+pub use input::Photo;
+pub use keying::KeyMetric;
+pub use manifest::FishManifest;
+pub use options::{
+    Background,
+    ScanOptions,
+    DEFAULT_BG_COLOR,
+};
+pub use quality::QualityReport;
+
+use background::{
+    estimate_background,
+    estimate_plate_key,
+};
+use correction::{
+    apply_gains,
+    despill,
+    white_balance_gains,
+    WhiteReference,
+};
+use crop::{
+    crop,
+    CropRect,
+};
+use debug::DebugDump;
+use fill::fill_edges;
+use heightmap::heightmap;
+use input::{
+    open_image,
+    MaskImage,
+    Rgb16Image,
+    to_u8,
+    to_u16,
+};
+use keying::{
+    difference_mask,
+    Keyer,
+};
+use lighting::{
+    correct_with_fit,
+    correct_with_plate,
+    load_plate,
+    FlatField,
+};
+use morphology::{
+    Bitmap,
+    BoundingBox,
+    Component,
+    ComponentFilter,
+    MaskCleanup,
+};
+use normals::{
+    normal_map,
+    surface_heightmap,
+};
+use orientation::{
+    head_on_left,
+    orient,
+    principal_angle,
+    OrientMode,
+    Orientation,
+};
+use perspective::{
+    find_paper,
+    rectify_paper,
+    Paper,
+};
+use quality::{
+    border_contact,
+    key_confidence,
+    sharpness,
+};
+
+pub type ColorsImage = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+/// The two textures `sea` needs to render a fish.
+pub struct FishSkin {
+    pub colors: ColorsImage,
+    /// whether the color channels of `colors` are multiplied by alpha
+    pub premultiplied_alpha: bool,
+    /// 8 bit rgb, or 16 bit if `NormalOptions::sixteen_bit` was set
+    pub normals: DynamicImage,
+    /// the background key that was used, `None` if the photo brought its own alpha mask
+    pub key: Option<BackgroundKey>,
+    /// where the fish was found in the photo
+    pub bbox: BoundingBox,
+    /// how the photo was turned to get the head to the right
+    pub orientation: Orientation,
+    /// the part of the (turned) photo that was scaled into the textures
+    pub crop: CropRect,
+    /// measurements and warnings, see `quality`
+    pub quality: QualityReport,
+}
+
+impl FishSkin {
+    pub fn save<P: AsRef<Path>, Q: AsRef<Path>>(&self, colors_path: P, normals_path: Q) -> Result<(), ScanError> {
+        self.colors.save(colors_path)?;
+        self.normals.save(normals_path)?;
+        Ok(())
+    }
+}
+
+/// Opens the photo at `path` and turns it into a fish skin.
+///
+/// With `options.debug_dir`, the stages are dumped into a subdirectory named after the photo.
+pub fn load_fish_skin<P: AsRef<Path>>(path: P, options: &ScanOptions) -> Result<FishSkin, ScanError> {
+    first_skin(load_fish_skins(path, options)?)
+}
+
+/// Same as `load_fish_skin`, for photos that are already in memory.
+///
+/// Any pixel format is accepted. If the image has a (not fully opaque) alpha channel,
+/// it is used as the mask directly and no background keying happens.
+pub fn fish_skin_from_image(image: DynamicImage, options: &ScanOptions) -> Result<FishSkin, ScanError> {
+    fish_skin_from_photo(Photo::from(image), options)
+}
+
+/// Opens the photo at `path` and turns every fish on it into a skin, see `fish_skins_from_photo`.
+pub fn load_fish_skins<P: AsRef<Path>>(path: P, options: &ScanOptions) -> Result<Vec<Result<FishSkin, ScanError>>, ScanError> {
+    let path = path.as_ref();
+    let image = open_image(path)?;
+    match (&options.debug_dir, path.file_name()) {
+        (Some(dir), Some(name)) => {
+            let options = ScanOptions { debug_dir: Some(dir.join(name)), ..options.clone() };
+            fish_skins_from_image(image, &options)
+        }
+        _ => fish_skins_from_image(image, options),
+    }
+}
+
+/// Same as `load_fish_skins`, for photos that are already in memory.
+pub fn fish_skins_from_image(image: DynamicImage, options: &ScanOptions) -> Result<Vec<Result<FishSkin, ScanError>>, ScanError> {
+    fish_skins_from_photo(Photo::from(image), options)
+}
+
+/// Picks color and distance threshold for keying the background of `photo`.
+/// With `Background::Plate` they are estimated from the border, like `Background::Auto`.
+pub fn background_key(photo: &Photo, options: &ScanOptions) -> BackgroundKey {
+    let mut key = match options.background {
+        Background::Color(color) => BackgroundKey { color, distance: options.key_metric.default_distance() },
+        Background::Auto | Background::Plate => estimate_background(&photo.rgb, options.key_metric),
+    };
+    if let Some(distance) = options.key_distance {
+        key.distance = distance;
+    }
+    key
+}
+
+/// Separates fish from backdrop: by color, or by comparing with the background plate.
+fn key_photo(photo: &Photo, options: &ScanOptions) -> Result<(BackgroundKey, MaskImage), ScanError> {
+    if options.background != Background::Plate {
+        let key = background_key(photo, options);
+        let mask = Keyer::new(options.key_metric, key.color).mask(&photo.rgb, key.distance, options.key_falloff);
+        return Ok((key, mask));
+    }
+    let path = options.background_plate.as_ref().ok_or_else(|| {
+        ScanError::InvalidOption("plate differencing needs a background plate".to_string())
+    })?;
+    let (width, height) = photo.dimensions();
+    let plate = load_plate(path, width, height)?;
+    let mut key = estimate_plate_key(&photo.rgb, &plate, options.key_metric);
+    if let Some(distance) = options.key_distance {
+        key.distance = distance;
+    }
+    let mask = difference_mask(options.key_metric, &photo.rgb, &plate, key.distance, options.key_falloff);
+    Ok((key, mask))
+}
+
+/// The colors of `photo` the backdrop is keyed from: with `options.flat_field`, the lighting
+/// evened out, otherwise the photo as it is.
+pub fn keyed_colors(photo: &Photo, options: &ScanOptions) -> Result<Rgb16Image, ScanError> {
+    match options.flat_field {
+        // the plate is compared with the photo as it was taken
+        Some(_) if options.background == Background::Plate => {
+            Err(ScanError::InvalidOption("plate differencing can't be combined with flat field correction".to_string()))
+        }
+        Some(mode) => flat_field(photo, mode, options),
+        None => Ok(photo.rgb.clone()),
+    }
+}
+
+/// Evens out the lighting of `photo`, see `lighting`.
+fn flat_field(photo: &Photo, mode: FlatField, options: &ScanOptions) -> Result<Rgb16Image, ScanError> {
+    let (width, height) = photo.dimensions();
+    match mode {
+        FlatField::Divide | FlatField::Subtract => {
+            let path = options.background_plate.as_ref().ok_or_else(|| {
+                ScanError::InvalidOption("flat field correction with a plate needs a background plate".to_string())
+            })?;
+            let plate = load_plate(path, width, height)?;
+            Ok(correct_with_plate(&photo.rgb, &plate, mode))
+        }
+        FlatField::Fit => {
+            if let Some(alpha) = &photo.alpha {
+                return Ok(correct_with_fit(&photo.rgb, &Bitmap::from_mask(alpha, 1)));
+            }
+            // a generous key, so the fish and its shadow stay out of the fit even where the
+            // lighting is off. That leaves out dark corners too, so the first fit is only good
+            // enough to find the backdrop for a second one
+            let mut corrected = Photo { rgb: photo.rgb.clone(), alpha: None };
+            for _ in 0..2 {
+                let key = background_key(&corrected, options);
+                let rough = Keyer::new(options.key_metric, key.color).mask(&corrected.rgb, key.distance * 2.0, 0.0);
+                let margin = (width.max(height) / 100).max(1);
+                let fish = Bitmap::from_mask(&rough, 1).dilate(margin);
+                corrected.rgb = correct_with_fit(&photo.rgb, &fish);
+            }
+            Ok(corrected.rgb)
+        }
+    }
+}
+
+/// Turns `photo` into a fish skin. With `options.debug_dir`, every stage is written there,
+/// also when the scan fails.
+///
+/// With `options.multiple_fish`, this is the first fish in reading order, see `fish_skins_from_photo`.
+pub fn fish_skin_from_photo(photo: Photo, options: &ScanOptions) -> Result<FishSkin, ScanError> {
+    first_skin(fish_skins_from_photo(photo, options)?)
+}
+
+/// Turns `photo` into fish skins: one, or with `options.multiple_fish` one per fish on the
+/// photo, in reading order (rows from top to bottom, left to right within a row).
+///
+/// The outer error is for the whole photo, e.g. when no fish was found at all. Every fish
+/// can still fail on its own, e.g. when a quality check rejects it.
+/// With `options.debug_dir`, the stages of the whole photo are written there and those of
+/// each fish into a `fish-N` subdirectory, also when the scan fails.
+pub fn fish_skins_from_photo(photo: Photo, options: &ScanOptions) -> Result<Vec<Result<FishSkin, ScanError>>, ScanError> {
+    let mut debug = DebugDump::new(options.debug_dir.as_deref());
+    let result = scan(photo, options, &mut debug);
+    // the stages before a failure are the interesting ones, so write them in any case
+    let written = debug.write();
+    let skins = result?;
+    written?;
+    Ok(skins)
+}
+
+fn first_skin(skins: Vec<Result<FishSkin, ScanError>>) -> Result<FishSkin, ScanError> {
+    skins.into_iter().next().unwrap_or(Err(ScanError::EmptyMask))
+}
+
+/// The keyed photo, shared by all the fish on it.
+struct Keyed {
+    rgb: Rgb16Image,
+    key: Option<BackgroundKey>,
+    /// the mask as it came from keying (or the alpha channel)
+    raw_mask: MaskImage,
+    /// the thresholded mask before cleanup
+    solid: Bitmap,
+    /// white balance gains, if asked for
+    gains: Option<[f32; 3]>,
+    /// regions of `solid`
+    components: Vec<Component>,
+}
+
+fn scan(mut photo: Photo, options: &ScanOptions, debug: &mut DebugDump) -> Result<Vec<Result<FishSkin, ScanError>>, ScanError> {
+    options.output_dimensions()?;
+    options.normals.validate()?;
+    if options.multiple_fish && !(options.min_fish_area > 0.0 && options.min_fish_area < 1.0) {
+        return Err(ScanError::InvalidOption(format!("minimum fish area {} is not in (0, 1)", options.min_fish_area)));
+    }
+    debug.add("photo", || DynamicImage::ImageRgb16(photo.rgb.clone()));
+    if options.flat_field.is_some() {
+        photo.rgb = keyed_colors(&photo, options)?;
+        debug.add("flat field", || DynamicImage::ImageRgb16(photo.rgb.clone()));
+    }
+    // the gray card is where it is in the photo, straightening would move it
+    let card_gains = match &options.white_balance {
+        Some(balance) if matches!(balance.reference, WhiteReference::Region(_)) => {
+            Some(white_balance_gains(&photo.rgb, balance, None)?)
+        }
+        _ => None,
+    };
+    if let Some(paper) = options.paper {
+        if options.background == Background::Plate {
+            return Err(ScanError::InvalidOption("plate differencing can't be combined with straightening the paper".to_string()));
+        }
+        let corners = match paper {
+            Paper::Detect => find_paper(&photo, debug)?,
+            Paper::Corners(corners) => corners,
+        };
+        photo = rectify_paper(&photo, &corners)?;
+        debug.add("straightened paper", || DynamicImage::ImageRgb16(photo.rgb.clone()));
+    }
+    if options.template {
+        photo = template::rectify(&photo.rgb, debug)?;
+    }
+    let (key, raw_mask) = match photo.alpha.take() {
+        Some(alpha) => (None, alpha),
+        None => {
+            let (key, mask) = key_photo(&photo, options)?;
+            (Some(key), mask)
+        }
+    };
+    debug.add("raw mask", || DynamicImage::ImageLuma16(raw_mask.clone()));
+    let gains = match (&options.white_balance, card_gains) {
+        (_, Some(gains)) => Some(gains),
+        (Some(balance), None) => Some(white_balance_gains(&photo.rgb, balance, key.map(|key| key.color).as_ref())?),
+        (None, None) => None,
+    };
+
+    // a keyed mask is noisy, blurring and thresholding gets rid of jagged edges.
+    // an alpha channel is trusted as is.
+    let solid = match key {
+        Some(_) => {
+            let blurred = imageops::blur(&raw_mask, 2.5);
+            debug.add("blurred mask", || DynamicImage::ImageLuma16(blurred.clone()));
+            Bitmap::from_mask(&blurred, u16::MAX / 8 * 7)
+        }
+        None => Bitmap::from_mask(&raw_mask, u16::MAX / 2),
+    };
+    debug.add("solid mask", || DynamicImage::ImageLuma16(solid.to_mask()));
+    // several fish: every region that's big enough is one, `cleanup.components` doesn't apply
+    let cleanup = if options.multiple_fish {
+        MaskCleanup { components: ComponentFilter::MinArea(options.min_fish_area), ..options.cleanup }
+    } else {
+        options.cleanup
+    };
+    let fish = solid.cleaned(&cleanup);
+    debug.add("cleaned mask", || DynamicImage::ImageLuma16(fish.to_mask()));
+    let components = solid.components().1;
+    let keyed = Keyed { rgb: photo.rgb, key, raw_mask, solid, gains, components };
+    if !options.multiple_fish {
+        return Ok(vec![cut_out(&keyed, &fish, options, debug)]);
+    }
+    let regions = fish.regions();
+    if regions.is_empty() {
+        return Err(ScanError::EmptyMask);
+    }
+    Ok(regions.iter().enumerate().map(|(i, region)| {
+        let mut debug = debug.nested(&format!("fish-{}", i + 1));
+        debug.add("fish mask", || DynamicImage::ImageLuma16(region.to_mask()));
+        let skin = cut_out(&keyed, region, options, &mut debug);
+        let written = debug.write();
+        let skin = skin?;
+        written?;
+        Ok(skin)
+    }).collect())
+}
+
+/// Turns one fish of a keyed photo into a skin.
+fn cut_out(keyed: &Keyed, fish: &Bitmap, options: &ScanOptions, debug: &mut DebugDump) -> Result<FishSkin, ScanError> {
+    let (out_width, out_height) = options.output_dimensions()?;
+    let Keyed { rgb, key, raw_mask, solid, gains, components } = keyed;
+    let (key, gains) = (*key, *gains);
+    let dim = rgb.dimensions();
+    let bg_rgb = key.map(|key| key.color).unwrap_or(DEFAULT_BG_COLOR);
+    let bg_rgb16 = Rgb([to_u16(bg_rgb[0]), to_u16(bg_rgb[1]), to_u16(bg_rgb[2])]);
+    let bbox = fish.bounding_box().ok_or(ScanError::EmptyMask)?;
+
+    let mask = if key.is_some() && options.key_falloff <= 0.0 {
+        fish.to_mask()
+    } else {
+        // keep graded edges, but only around the cleaned up fish.
+        // filled holes become opaque
+        let near = fish.dilate(3);
+        ImageBuffer::from_fn(dim.0, dim.1, |x, y| {
+            if fish.get(x, y) && !solid.get(x, y) {
+                Luma([u16::MAX])
+            } else if near.get(x, y) {
+                *raw_mask.get_pixel(x, y)
+            } else {
+                Luma([0])
+            }
+        })
+    };
+
+    // a coloring page is straight and the right way round by construction
+    let orientation_mode = if options.template { OrientMode::Keep } else { options.orientation };
+    let orientation = match orientation_mode {
+        OrientMode::Keep => Orientation { angle: 0.0, mirrored: options.flip },
+        OrientMode::Auto => {
+            let angle = -principal_angle(fish).to_degrees();
+            // a level fish doesn't need resampling just for a fraction of a degree
+            let angle = if angle.abs() < 1.0 { 0.0 } else { angle };
+            let straight = orient(&fish.to_mask(), &bbox, &Orientation { angle, mirrored: false }, Luma([0]));
+            let straight = Bitmap::from_mask(&straight, u16::MAX / 2);
+            let mirrored = match straight.bounding_box() {
+                Some(straight_bbox) => head_on_left(&straight, &straight_bbox),
+                None => false,
+            };
+            Orientation { angle, mirrored: mirrored != options.flip }
+        }
+    };
+    let turned;
+    let (img, mask, bbox_oriented) = if orientation.is_identity() {
+        (rgb, mask, bbox)
+    } else {
+        turned = orient(rgb, &bbox, &orientation, bg_rgb16);
+        let img = &turned;
+        let mask = orient(&mask, &bbox, &orientation, Luma([0]));
+        debug.add("turned photo", || DynamicImage::ImageRgb16(turned.clone()));
+        debug.add("turned mask", || DynamicImage::ImageLuma16(mask.clone()));
+        let bbox = Bitmap::from_mask(&mask, u16::MAX / 2).bounding_box().ok_or(ScanError::EmptyMask)?;
+        (img, mask, bbox)
+    };
+
+    // everything below was tuned for 1024 x 1024, `scale` keeps the look at other sizes
+    let long_side = out_width.max(out_height);
+    let scale = long_side as f32 / 1024.0;
+
+    let rect = CropRect::around(&bbox_oriented, options.aspect_ratio, options.padding);
+    let crop_mask = crop(&mask, &rect, Luma([0]));
+    let crop_img = crop(img, &rect, bg_rgb16);
+    debug.add("crop mask", || DynamicImage::ImageLuma16(crop_mask.clone()));
+
+    let out_mask = imageops::resize(&crop_mask, out_width, out_height, FilterType::CatmullRom);
+    let out_img = imageops::resize(&crop_img, out_width, out_height, FilterType::CatmullRom);
+    let grey_img = imageops::colorops::grayscale(&out_img);
+    debug.add("grayscale detail", || DynamicImage::ImageLuma16(grey_img.clone()));
+
+    let heightmap = heightmap(&out_mask, options.height_model, scale, debug);
+    debug.add("heightmap", || DynamicImage::ImageLuma16(heightmap.clone()));
+
+    let heightmap = surface_heightmap(&heightmap, &grey_img, &options.normals, scale, debug);
+    debug.add("heightmap with surface", || DynamicImage::ImageLuma16(heightmap.clone()));
+    let normals = normal_map(&heightmap, &options.normals, scale);
+    debug.add("normals", || normals.clone());
+
+    let out_img = match key {
+        Some(key) if options.despill => despill(&out_img, &out_mask, &key.color, scale),
+        _ => out_img,
+    };
+    let out_img = match gains {
+        Some(gains) => apply_gains(&out_img, gains),
+        None => out_img,
+    };
+    if options.despill || gains.is_some() {
+        debug.add("corrected colors", || DynamicImage::ImageRgb16(out_img.clone()));
+    }
+    let filled_img = fill_edges(&out_img, &out_mask, options.edge_fill);
+    debug.add("filled edges", || DynamicImage::ImageRgb16(filled_img.clone()));
+    let colors = ImageBuffer::from_fn(out_width, out_height, |x, y| {
+        let color = filled_img.get_pixel(x,y);
+        let alpha = out_mask.get_pixel(x,y);
+        let premultiply = |c: u16| if options.premultiplied_alpha {
+            (c as u32 * alpha[0] as u32 / u16::MAX as u32) as u16
+        } else {
+            c
+        };
+        Rgba([
+             to_u8(premultiply(color[0])),
+             to_u8(premultiply(color[1])),
+             to_u8(premultiply(color[2])),
+             to_u8(alpha[0])
+        ])
+    });
+
+    debug.add("colors", || DynamicImage::ImageRgba8(colors.clone()));
+
+    // plate differencing has no single color to measure against
+    let keyer = match key {
+        Some(key) if options.background != Background::Plate => Some((Keyer::new(options.key_metric, key.color), key.distance)),
+        _ => None,
+    };
+    let mut quality = QualityReport {
+        coverage: fish.area() as f32 / (dim.0 as f32 * dim.1 as f32),
+        border_contact: border_contact(fish),
+        // only the specks around this fish, not around its neighbours
+        components: components.iter().filter(|c| c.bbox.overlaps(&bbox)).count(),
+        key_confidence: keyer.map(|(keyer, distance)| key_confidence(rgb, fish, &bbox, &keyer, distance)),
+        sharpness: sharpness(rgb, fish, &bbox),
+        findings: Vec::new(),
+    };
+    options.quality.judge(&mut quality);
+    if quality.rejected() {
+        return Err(ScanError::Rejected(Box::new(quality)));
+    }
+
+    Ok(FishSkin { colors, premultiplied_alpha: options.premultiplied_alpha, normals, key, bbox, orientation, crop: rect, quality })
+}
@@ -0,0 +1,173 @@
+//! Flat-field correction: evening out vignetting and lamp hot-spots before keying.
+
+use image::{
+    imageops,
+    imageops::FilterType,
+    ImageBuffer,
+    Rgb,
+};
+
+use crate::{
+    color::{
+        linear_to_srgb16,
+        srgb16_to_linear,
+    },
+    error::ScanError,
+    input::{
+        open_image,
+        Rgb16Image,
+    },
+    linalg::solve,
+    morphology::Bitmap,
+};
+
+use std::{
+    path::Path,
+    str::FromStr,
+};
+
+/// the plate is smoothed at about this size, so its grain and dust don't end up in the photo
+const PLATE_SMOOTH_SIZE: u32 = 256;
+/// at most this many background pixels go into the surface fit
+const MAX_FIT_SAMPLES: usize = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlatField {
+    /// divide by a photo of the empty backdrop: undoes vignetting and uneven lamps
+    Divide,
+    /// subtract a photo of the empty backdrop: for glare and haze that add light
+    Subtract,
+    /// fit a smooth (quadratic) surface to the backdrop around the fish and divide by it,
+    /// no extra photo needed
+    Fit,
+}
+
+impl FromStr for FlatField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "divide" => Ok(FlatField::Divide),
+            "subtract" => Ok(FlatField::Subtract),
+            "fit" => Ok(FlatField::Fit),
+            _ => Err(format!("unknown flat field mode: {} (divide, subtract, fit)", s)),
+        }
+    }
+}
+
+/// Opens a photo of the empty backdrop, shot with the same camera position and lamps.
+pub fn load_plate<P: AsRef<Path>>(path: P, width: u32, height: u32) -> Result<Rgb16Image, ScanError> {
+    let plate = open_image(path)?.into_rgb16();
+    if plate.dimensions() != (width, height) {
+        let (plate_width, plate_height) = plate.dimensions();
+        return Err(ScanError::InvalidOption(format!(
+            "background plate is {}x{}, the photo {}x{}", plate_width, plate_height, width, height,
+        )));
+    }
+    Ok(plate)
+}
+
+/// Evens out the lighting of `img` with a photo of the empty backdrop (`Divide` or `Subtract`).
+/// The backdrop ends up at the plate's average color.
+pub fn correct_with_plate(img: &Rgb16Image, plate: &Rgb16Image, mode: FlatField) -> Rgb16Image {
+    let (width, height) = img.dimensions();
+    let scale = PLATE_SMOOTH_SIZE as f32 / width.max(height) as f32;
+    let small = imageops::resize(
+        plate,
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+        FilterType::Triangle,
+    );
+    let small = imageops::blur(&small, 2.0);
+    let smooth = imageops::resize(&small, width, height, FilterType::Triangle);
+
+    let mut mean = [0f64; 3];
+    for p in smooth.pixels() {
+        for (c, m) in mean.iter_mut().enumerate() {
+            *m += srgb16_to_linear(p[c]) as f64;
+        }
+    }
+    let mean = mean.map(|m| (m / (width as f64 * height as f64)) as f32);
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let p = img.get_pixel(x, y);
+        let light = smooth.get_pixel(x, y);
+        Rgb([0, 1, 2].map(|c| {
+            let (value, light) = (srgb16_to_linear(p[c]), srgb16_to_linear(light[c]));
+            match mode {
+                FlatField::Subtract => linear_to_srgb16(value - light + mean[c]),
+                _ => linear_to_srgb16(value * mean[c] / light.max(1e-4)),
+            }
+        }))
+    })
+}
+
+/// A quadratic surface over the image, in coordinates scaled to [-1, 1].
+struct Surface([f32; 6]);
+
+impl Surface {
+    fn terms(u: f32, v: f32) -> [f32; 6] {
+        [1.0, u, v, u * u, u * v, v * v]
+    }
+
+    fn at(&self, u: f32, v: f32) -> f32 {
+        Surface::terms(u, v).iter().zip(self.0.iter()).map(|(t, c)| t * c).sum()
+    }
+
+    /// least squares fit to `(u, v, value)` samples, `None` if they don't pin the surface down
+    fn fit(samples: &[(f32, f32, f32)]) -> Option<Surface> {
+        // normal equations
+        let (mut a, mut b) = ([[0f64; 6]; 6], [0f64; 6]);
+        for &(u, v, value) in samples {
+            let t = Surface::terms(u, v);
+            for i in 0..6 {
+                for j in 0..6 {
+                    a[i][j] += (t[i] * t[j]) as f64;
+                }
+                b[i] += (t[i] * value) as f64;
+            }
+        }
+        Some(Surface(solve(a, b)?.map(|c| c as f32)))
+    }
+}
+
+/// Fits a smooth illumination surface to the backdrop, the pixels of `img` that are not set
+/// in `fish`, and divides it out. The backdrop ends up at its average color.
+/// Returns `img` unchanged if there is too little backdrop to fit.
+pub fn correct_with_fit(img: &Rgb16Image, fish: &Bitmap) -> Rgb16Image {
+    let (width, height) = img.dimensions();
+    let coordinates = |x: u32, y: u32| {
+        (2.0 * x as f32 / width as f32 - 1.0, 2.0 * y as f32 / height as f32 - 1.0)
+    };
+    let backdrop_area = (width * height - fish.area()) as usize;
+    let step = ((backdrop_area / MAX_FIT_SAMPLES) as f32).sqrt().max(1.0) as u32;
+    let mut samples = [Vec::new(), Vec::new(), Vec::new()];
+    for y in (0..height).step_by(step as usize) {
+        for x in (0..width).step_by(step as usize) {
+            if !fish.get(x, y) {
+                let (u, v) = coordinates(x, y);
+                let p = img.get_pixel(x, y);
+                for (c, channel) in samples.iter_mut().enumerate() {
+                    channel.push((u, v, srgb16_to_linear(p[c])));
+                }
+            }
+        }
+    }
+    let surfaces: Option<Vec<Surface>> = samples.iter().map(|channel| Surface::fit(channel)).collect();
+    let surfaces = match surfaces {
+        Some(surfaces) => surfaces,
+        None => return img.clone(),
+    };
+    let mean: Vec<f32> = samples.iter().map(|channel| {
+        channel.iter().map(|s| s.2).sum::<f32>() / channel.len() as f32
+    }).collect();
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (u, v) = coordinates(x, y);
+        let p = img.get_pixel(x, y);
+        Rgb([0, 1, 2].map(|c| {
+            let light = surfaces[c].at(u, v).max(mean[c] * 0.1).max(1e-4);
+            linear_to_srgb16(srgb16_to_linear(p[c]) * mean[c] / light)
+        }))
+    })
+}
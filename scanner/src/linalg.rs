@@ -0,0 +1,29 @@
+//! Small dense linear systems, for the least squares fits and the homography.
+
+/// Solves `a x = b` by Gaussian elimination with partial pivoting.
+/// `None` if `a` is (nearly) singular.
+pub fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+        if a[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in 0..N {
+            if row != col {
+                let f = a[row][col] / a[col][col];
+                let pivot_row = a[col];
+                for (value, p) in a[row].iter_mut().zip(pivot_row.iter()) {
+                    *value -= f * p;
+                }
+                b[row] -= f * b[col];
+            }
+        }
+    }
+    let mut x = [0f64; N];
+    for (i, value) in x.iter_mut().enumerate() {
+        *value = b[i] / a[i][i];
+    }
+    Some(x)
+}
@@ -0,0 +1,322 @@
+//! Lossless JPEG (ITU T.81 process 14), the compression inside CR2 and most DNG files.
+
+use std::convert::TryInto;
+
+/// A decoded frame: `height` rows of `width * components` interleaved samples.
+pub struct LosslessJpeg {
+    pub width: usize,
+    pub height: usize,
+    pub components: usize,
+    pub precision: u8,
+    pub data: Vec<u16>,
+}
+
+/// Canonical Huffman table, looked up with the next 16 bits of the stream.
+struct HuffmanTable {
+    /// code length and symbol for every 16 bit prefix, length 0 for invalid codes
+    lookup: Vec<(u8, u8)>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8; 16], symbols: &[u8]) -> Result<HuffmanTable, String> {
+        let mut lookup = vec![(0, 0); 1 << 16];
+        let mut code = 0u32;
+        let mut symbols = symbols.iter();
+        for (i, &count) in counts.iter().enumerate() {
+            let length = i as u32 + 1;
+            for _ in 0..count {
+                let symbol = *symbols.next().ok_or("huffman table is too short")?;
+                // lossless jpeg codes differences of at most 16 bits
+                if symbol > 16 {
+                    return Err(format!("invalid huffman symbol {}", symbol));
+                }
+                if code >= 1 << length {
+                    return Err("invalid huffman table".to_string());
+                }
+                let first = (code << (16 - length)) as usize;
+                for entry in &mut lookup[first..first + (1 << (16 - length))] {
+                    *entry = (length as u8, symbol);
+                }
+                code += 1;
+            }
+            code <<= 1;
+        }
+        Ok(HuffmanTable { lookup })
+    }
+}
+
+/// Reads the entropy coded segment, skipping stuffed zero bytes. Stops at the next marker
+/// and feeds zeros from then on.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u64,
+    count: u32,
+    at_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> BitReader<'a> {
+        BitReader { data, pos, bits: 0, count: 0, at_marker: false }
+    }
+
+    fn fill(&mut self) {
+        while self.count <= 56 {
+            let mut byte = 0;
+            if !self.at_marker && self.pos < self.data.len() {
+                byte = self.data[self.pos];
+                if byte == 0xFF {
+                    if self.data.get(self.pos + 1) == Some(&0) {
+                        self.pos += 2;
+                    } else {
+                        self.at_marker = true;
+                        byte = 0;
+                    }
+                } else {
+                    self.pos += 1;
+                }
+            }
+            self.bits |= (byte as u64) << (56 - self.count);
+            self.count += 8;
+        }
+    }
+
+    fn take(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        self.fill();
+        let value = (self.bits >> (64 - n)) as u32;
+        self.bits <<= n;
+        self.count -= n;
+        value
+    }
+
+    fn decode(&mut self, table: &HuffmanTable) -> Result<u8, String> {
+        self.fill();
+        let (length, symbol) = table.lookup[(self.bits >> 48) as usize];
+        if length == 0 {
+            return Err("invalid huffman code".to_string());
+        }
+        self.bits <<= length;
+        self.count -= length as u32;
+        Ok(symbol)
+    }
+
+    /// Skips the restart marker the reader stopped at and starts over with an empty buffer.
+    fn restart(&mut self) -> Result<(), String> {
+        while self.pos < self.data.len() && self.data[self.pos] == 0xFF {
+            self.pos += 1;
+        }
+        match self.data.get(self.pos) {
+            Some(0xD0..=0xD7) => self.pos += 1,
+            _ => return Err("missing restart marker".to_string()),
+        }
+        self.bits = 0;
+        self.count = 0;
+        self.at_marker = false;
+        Ok(())
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<usize, String> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(((bytes[0] as usize) << 8) | bytes[1] as usize),
+        None => Err("unexpected end of data".to_string()),
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<LosslessJpeg, String> {
+    if data.get(0..2) != Some(&[0xFF, 0xD8]) {
+        return Err("not a jpeg stream".to_string());
+    }
+    let mut tables: [Option<HuffmanTable>; 4] = [None, None, None, None];
+    let mut frame: Option<(usize, usize, u8, Vec<u8>)> = None;
+    let mut restart_interval = 0;
+    let mut pos = 2;
+    loop {
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        if data.get(pos) != Some(&0xFF) {
+            return Err("expected a marker".to_string());
+        }
+        let marker = *data.get(pos + 1).ok_or("unexpected end of data")?;
+        let length = read_u16(data, pos + 2)?;
+        let segment = data.get(pos + 4..pos + 2 + length).ok_or("unexpected end of data")?;
+        match marker {
+            0xC4 => {
+                let mut rest = segment;
+                while !rest.is_empty() {
+                    let id = (rest[0] & 0x0F) as usize;
+                    let counts: [u8; 16] = rest.get(1..17).ok_or("truncated huffman table")?.try_into().unwrap();
+                    let total: usize = counts.iter().map(|&c| c as usize).sum();
+                    let symbols = rest.get(17..17 + total).ok_or("truncated huffman table")?;
+                    if id >= tables.len() {
+                        return Err(format!("huffman table id {} out of range", id));
+                    }
+                    tables[id] = Some(HuffmanTable::new(&counts, symbols)?);
+                    rest = &rest[17 + total..];
+                }
+            }
+            0xC3 => {
+                let precision = *segment.first().ok_or("truncated frame header")?;
+                let height = read_u16(segment, 1)?;
+                let width = read_u16(segment, 3)?;
+                let components = *segment.get(5).ok_or("truncated frame header")? as usize;
+                if width == 0 || height == 0 || components == 0 {
+                    return Err("empty frame".to_string());
+                }
+                // the shortest huffman code has one bit
+                if width * height * components > data.len() * 8 {
+                    return Err("frame larger than the jpeg stream".to_string());
+                }
+                let mut ids = Vec::new();
+                for c in 0..components {
+                    let component = segment.get(6 + c * 3..9 + c * 3).ok_or("truncated frame header")?;
+                    if component[1] != 0x11 {
+                        return Err("subsampled components are not supported".to_string());
+                    }
+                    ids.push(component[0]);
+                }
+                frame = Some((width, height, precision, ids));
+            }
+            0xC0..=0xCF => return Err(format!("not a lossless jpeg (SOF{})", marker - 0xC0)),
+            0xDD => restart_interval = read_u16(segment, 0)?,
+            0xDA => {
+                let (width, height, precision, ids) = frame.ok_or("scan before frame header")?;
+                let count = *segment.first().ok_or("truncated scan header")? as usize;
+                if count != ids.len() {
+                    return Err("scans with a subset of the components are not supported".to_string());
+                }
+                let mut component_tables = Vec::new();
+                for c in 0..count {
+                    let id = segment.get(1 + c * 2).ok_or("truncated scan header")?;
+                    let table = (segment.get(2 + c * 2).ok_or("truncated scan header")? >> 4) as usize;
+                    if !ids.contains(id) {
+                        return Err(format!("unknown component {}", id));
+                    }
+                    let table = tables.get(table).and_then(|t| t.as_ref()).ok_or("missing huffman table")?;
+                    component_tables.push(table);
+                }
+                let predictor = *segment.get(1 + count * 2).ok_or("truncated scan header")?;
+                let point_transform = segment.get(3 + count * 2).ok_or("truncated scan header")? & 0x0F;
+                let reader = BitReader::new(data, pos + 2 + length);
+                let samples = decode_scan(reader, &component_tables, width, height, precision, predictor, point_transform, restart_interval)?;
+                return Ok(LosslessJpeg { width, height, components: count, precision, data: samples });
+            }
+            0xD9 => return Err("no scan in jpeg stream".to_string()),
+            _ => (),
+        }
+        pos += 2 + length;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn decode_scan(
+    mut reader: BitReader,
+    tables: &[&HuffmanTable],
+    width: usize,
+    height: usize,
+    precision: u8,
+    predictor: u8,
+    point_transform: u8,
+    restart_interval: usize,
+) -> Result<Vec<u16>, String> {
+    if !(1..=7).contains(&predictor) {
+        return Err(format!("unknown predictor {}", predictor));
+    }
+    if !(2..=16).contains(&precision) || point_transform >= precision {
+        return Err(format!("invalid precision {} with point transform {}", precision, point_transform));
+    }
+    if restart_interval > 0 && !restart_interval.is_multiple_of(width) {
+        return Err("restart intervals that don't end with a row are not supported".to_string());
+    }
+    let components = tables.len();
+    let stride = width * components;
+    let mut out = vec![0u16; stride * height];
+    let initial = 1i32 << (precision - point_transform - 1);
+    let mut first_row = true;
+    for y in 0..height {
+        if restart_interval > 0 && y > 0 && (y * width).is_multiple_of(restart_interval) {
+            reader.restart()?;
+            first_row = true;
+        }
+        for x in 0..width {
+            for (c, table) in tables.iter().enumerate() {
+                let length = reader.decode(table)? as u32;
+                let diff = match length {
+                    0 => 0,
+                    16 => 32768,
+                    _ => {
+                        let bits = reader.take(length) as i32;
+                        // values with a leading zero bit are negative
+                        if bits < 1 << (length - 1) { bits - (1 << length) + 1 } else { bits }
+                    }
+                };
+                let i = y * stride + x * components + c;
+                let left = || out[i - components] as i32;
+                let above = || out[i - stride] as i32;
+                let prediction = if first_row {
+                    if x == 0 { initial } else { left() }
+                } else if x == 0 {
+                    above()
+                } else {
+                    let (a, b, corner) = (left(), above(), out[i - stride - components] as i32);
+                    match predictor {
+                        1 => a,
+                        2 => b,
+                        3 => corner,
+                        4 => a + b - corner,
+                        5 => a + ((b - corner) >> 1),
+                        6 => b + ((a - corner) >> 1),
+                        _ => (a + b) >> 1,
+                    }
+                };
+                out[i] = (prediction + diff) as u16;
+            }
+        }
+        first_row = false;
+    }
+    if point_transform > 0 {
+        for value in &mut out {
+            *value <<= point_transform;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2, 8 bit gray frame predicted from the left: 128 130 / 127 127.
+    /// Huffman codes: 0 for a difference of 0, 10 and 11 for one and two bit differences.
+    fn stream(precision: u8, symbols: [u8; 3]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        data.extend_from_slice(&[0xFF, 0xC4, 0x00, 0x16, 0x00, 1, 2]);
+        data.extend_from_slice(&[0; 14]);
+        data.extend_from_slice(&symbols);
+        data.extend_from_slice(&[0xFF, 0xC3, 0x00, 0x0B, precision, 0x00, 0x02, 0x00, 0x02, 0x01, 0x01, 0x11, 0x00]);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00]);
+        // 0 | 11 10 | 10 0 | 0, padded with ones
+        data.extend_from_slice(&[0b0111_0100, 0b0111_1111]);
+        data.extend_from_slice(&[0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn decodes_a_small_frame() {
+        let jpeg = decode(&stream(8, [0, 1, 2])).unwrap();
+        assert_eq!((jpeg.width, jpeg.height, jpeg.components, jpeg.precision), (2, 2, 1, 8));
+        assert_eq!(jpeg.data, vec![128, 130, 127, 127]);
+    }
+
+    #[test]
+    fn rejects_broken_headers() {
+        assert!(decode(&stream(0, [0, 1, 2])).is_err());
+        assert!(decode(&stream(1, [0, 1, 2])).is_err());
+        assert!(decode(&stream(8, [0, 1, 17])).is_err());
+        assert!(decode(&stream(8, [0, 1, 2])[..30]).is_err());
+    }
+}
@@ -1,189 +1,522 @@
-use image::{
-    ImageBuffer,
-    Rgb, 
-    Rgba, 
-    Luma, 
-    imageops, 
-    imageops::{
-        FilterType,
+use image::Rgb;
+
+use scanner::{
+    batch::{
+        scan_directory,
+        scan_photo,
+        BatchEntry,
+    },
+    capture::{
+        CaptureError,
+        CaptureSource,
+        Gphoto2,
+        Replay,
+    },
+    correction::{
+        WhiteBalance,
+        WhiteReference,
     },
-    DynamicImage,
+    crop::CropRect,
+    lighting::FlatField,
+    output::next_fish_number,
+    perspective::Paper,
+    quality::QualityRules,
+    styler::Styler,
+    template,
+    watch::Watcher,
+    load_fish_skins,
+    Background,
+    FishManifest,
+    FishSkin,
+    ScanError,
+    ScanOptions,
 };
 
-use std::env; 
+use std::{
+    env,
+    fs,
+    io::{
+        self,
+        BufRead,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process,
+    str::FromStr,
+    time::Duration,
+};
 
-const DEFAULT_BG_COLOR: Rgb<u8> = Rgb([18,18,18]); // default: almost black
-const COL_DISTANCE_SQUARED: u32 = 20 * 20;
+fn usage() {
+    println!("usage: scanner <image file> [<r> <g> <b> | auto | plate] [options]");
+    println!("       scanner batch <input dir> --out <output dir> [options]");
+    println!("       scanner watch <input dir> --out <output dir> [--state <file>] [--interval <s>] [options]");
+    println!("       scanner capture <photo dir> --out <output dir> [--camera <model> | --replay <dir>] [--timeout <s>] [options]");
+    println!("       scanner styler <photo dir> --out <output dir> [--listen <address>] [--camera <model> | --replay <dir>] [options]");
+    println!("       scanner template <file.png | file.svg> [--dpi <d>]");
+    println!();
+    println!("  <r> <g> <b>             background color, each in [0,255]");
+    println!("  auto                    estimate the background color from the image border");
+    println!("  plate                   no single color: compare with --background-plate pixel by pixel");
+    println!("  --bg <r,g,b|auto|plate> same as the positional background color");
+    println!("  --out <dir>             where batch and watch mode write NNNN-colors.png / NNNN-normals.png");
+    println!("  --state <file>          watch mode: where processed files are remembered,");
+    println!("                          default <input dir>/.scanner-watch");
+    println!("  --interval <s>          watch mode: seconds between two looks at the directory, default 1");
+    println!("  --camera <model>        capture and styler mode: the camera as gphoto2 calls it, e.g. \"Canon EOS 600D\",");
+    println!("                          capture mode defaults to the first one connected");
+    println!("  --replay <dir>          capture and styler mode: hand out the photos in <dir> one by one,");
+    println!("                          instead of using a camera");
+    println!("  --timeout <s>           capture and styler mode: seconds to wait for the camera, default 30");
+    println!("  --listen <address>      styler mode: where the web app is served, default 127.0.0.1:8080");
+    println!("  --dpi <d>               resolution of a png coloring page, default 150");
+    println!("  --template              the photo shows a coloring page: cut the fish out along its marks");
+    println!("  --paper <p>             straighten a sheet of paper photographed at an angle: auto, or its");
+    println!("                          corners x1,y1,..,x4,y4 clockwise from the top left of the drawing");
+    println!("  --background-plate <f>  photo of the empty backdrop, same camera position and lamps");
+    println!("  --flat-field <m>        even out the lighting before keying: divide (default with a plate)");
+    println!("                          or subtract the plate, or fit a smooth surface to the backdrop");
+    println!("  --key-metric <m>        rgb (default), de76, de2000 or chroma");
+    println!("  --key-distance <d>      color distance below which a pixel counts as background");
+    println!("  --key-falloff <f>       width of the soft edge above the key distance, default 0");
+    println!("  --components <c>        which mask regions to keep: largest (default), all,");
+    println!("                          or a minimum area as fraction of the image, e.g. 0.01");
+    println!("  --multi                 the photo shows several fish, each becomes a skin of its own");
+    println!("  --min-fish-area <a>     with --multi: smallest fish as fraction of the image, default 0.002");
+    println!("  --keep-holes            don't fill holes inside the fish");
+    println!("  --open <r>              remove specks and strands thinner than 2r pixels");
+    println!("  --close <r>             bridge gaps narrower than 2r pixels");
+    println!("  --size <w>              width of the output textures, 256 to 4096, default 1024");
+    println!("  --aspect <a>            width / height of the outputs, e.g. 2 or 2:1, default 1");
+    println!("  --padding <p>           space around the fish in percent of its length, default 1");
+    println!("  --orient <o>            auto (default): level the fish and turn its head right,");
+    println!("                          keep: leave it as photographed");
+    println!("  --flip                  mirror the fish, for when the head ends up on the wrong side");
+    println!("  --height <h>            body bulge of the normal map: blur (default, the original look),");
+    println!("                          or distance based with a linear, sqrt or elliptical profile");
+    println!("  --normal-strength <s>   steepness of the normals, default 1");
+    println!("  --detail-weight <w>     share of the painted surface detail in the normals, 0 to 1, default 0.05");
+    println!("  --detail-blur <r>       blur radius of the surface detail in pixels, default 2");
+    println!("  --normal-convention <c> directx (default, what sea expects): green points down, opengl: up");
+    println!("  --gradient <g>          forward (default), central or sobel differences");
+    println!("  --normals-16bit         write the normal map as 16 bit png");
+    println!("  --despill               remove the backdrop's hue from the fish's outline");
+    println!("  --white-balance <r>     neutralize the lamp's color against a gray reference: backdrop,");
+    println!("                          or a gray card at x,y,w,h (pixels in the photo)");
+    println!("  --gray-level <v>        also set the exposure: the reference ends up at this gray (0-255),");
+    println!("                          118 for an 18% gray card");
+    println!("  --edge-fill <f>         color of the transparent surroundings: pull-push (default), flood,");
+    println!("                          or none (keeps the backdrop, which shows as a dark fringe)");
+    println!("  --premultiply           write the colors premultiplied by alpha");
+    println!("  --check <c>:<s>[:<l>]   what a failed quality check does: ignore, warn or reject, and its limit,");
+    println!("                          e.g. border:reject or sharpness:reject:0.3, checks: border, min-coverage,");
+    println!("                          max-coverage, components, key-confidence, sharpness");
+    println!("  --debug-dir <dir>       write every intermediate image and a contact sheet to <dir>/<photo>/");
+}
 
-pub fn main() {
-    let args: Vec<String> = env::args().collect(); 
-    println!("{:?}", args);
-    let mut bg_color = DEFAULT_BG_COLOR;
-    if args.len() == 5 {
-        bg_color = Rgb([
-            args[2].parse().unwrap(), 
-            args[3].parse().unwrap(), 
-            args[4].parse().unwrap()
-        ]);
-    } else if args.len() != 2 {
-        println!("please specify the path to the image file as the single parameter");
-        println!("or <image file> <r> <g> <b>, r,g,b background color [0,255]");
-        return 
-    }
-    let image_file = &args[1];
-    let option = load_fish_skin(image_file, bg_color);
-    assert!(option.is_ok()); 
-    let (colors, normals) = option.unwrap(); 
-    normals.save(format!("./{}_normals.png", image_file)).unwrap();
-    colors.save(format!("./{}_colors.png", image_file)).unwrap();
-}
-
-pub fn load_fish_skin(path: &String, bg_rgb: Rgb<u8>) -> Result<(ImageBuffer<Rgba<u8>, Vec<u8>>, ImageBuffer<Rgb<u8>, Vec<u8>>), String> {
-    match image::open(path) {
-        Ok(image) => {
-            println!("Image color format: {:?}", image.color());
-            match image {
-                DynamicImage::ImageRgb8(img) => {
-                    let dim = img.dimensions(); 
-                    let mask = ImageBuffer::from_fn(dim.0, dim.1, |x, y| {
-                        if rgb_distance_squared(img.get_pixel(x,y), &bg_rgb) < COL_DISTANCE_SQUARED {
-                            Luma([0 as u16])
-                        } else {
-                            Luma([std::u16::MAX])
-                        }
-                    });
-                    let mut blurred_mask = imageops::blur(&mask, 2.5); 
-                    let mut l = dim.1;
-                    let mut r = 0;
-                    let mut t = dim.1;
-                    let mut b = 0; 
-                    for (x, y, pixel) in blurred_mask.enumerate_pixels_mut() {
-                        if pixel[0] >= std::u16::MAX / 8 * 7 {
-                            l = l.min(x);
-                            l = l.min(x);
-                            r = r.max(x); 
-                            t = t.min(y); 
-                            b = b.max(y);  
-                            pixel[0] = std::u16::MAX;
-                        } else {
-                            pixel[0] = 0;
-                        }
+fn fail(message: &str) -> ! {
+    println!("{}", message);
+    usage();
+    process::exit(2);
+}
+
+fn parse<T: FromStr>(arg: &str) -> T {
+    match arg.parse() {
+        Ok(value) => value,
+        Err(_) => fail(&format!("invalid value: {}", arg)),
+    }
+}
+
+/// `2`, `0.5` or `2:1`
+fn parse_aspect(arg: &str) -> f32 {
+    match arg.find(':') {
+        Some(colon) => parse::<f32>(&arg[..colon]) / parse::<f32>(&arg[colon + 1..]),
+        None => parse(arg),
+    }
+}
+
+/// `auto`, `plate` or `r,g,b`
+fn parse_background(arg: &str) -> Background {
+    match arg {
+        "auto" => return Background::Auto,
+        "plate" => return Background::Plate,
+        _ => (),
+    }
+    let rgb: Vec<u8> = arg.split(',').map(parse).collect();
+    if rgb.len() != 3 {
+        fail(&format!("invalid background color: {}", arg));
+    }
+    Background::Color(Rgb([rgb[0], rgb[1], rgb[2]]))
+}
+
+/// `backdrop` or `x,y,w,h`
+fn parse_white_reference(arg: &str) -> WhiteReference {
+    if arg == "backdrop" {
+        return WhiteReference::Backdrop;
+    }
+    let region: Vec<u32> = arg.split(',').map(parse).collect();
+    if region.len() != 4 {
+        fail(&format!("invalid white balance reference: {}", arg));
+    }
+    WhiteReference::Region(CropRect { left: region[0] as i32, top: region[1] as i32, width: region[2], height: region[3] })
+}
+
+/// A plate alone means dividing by it, unless the fish is found by comparing with it.
+/// Needs the final background, which for a single photo can still come from the positional arguments.
+fn default_flat_field(options: &mut ScanOptions) {
+    if options.background_plate.is_some() && options.flat_field.is_none() && options.background != Background::Plate {
+        options.flat_field = Some(FlatField::Divide);
+    }
+}
+
+/// `auto` or `x1,y1,x2,y2,x3,y3,x4,y4`
+fn parse_paper(arg: &str) -> Paper {
+    if arg == "auto" {
+        return Paper::Detect;
+    }
+    let values: Vec<f32> = arg.split(',').map(parse).collect();
+    if values.len() != 8 {
+        fail(&format!("invalid paper corners: {}", arg));
+    }
+    Paper::Corners([(values[0], values[1]), (values[2], values[3]), (values[4], values[5]), (values[6], values[7])])
+}
+
+/// `<check>:<severity>` or `<check>:<severity>:<limit>`
+fn parse_check(arg: &str, rules: &mut QualityRules) {
+    let parts: Vec<&str> = arg.split(':').collect();
+    if !(2..=3).contains(&parts.len()) {
+        fail(&format!("invalid quality check: {}", arg));
+    }
+    let rule = rules.rule_mut(parse(parts[0]));
+    rule.severity = parse(parts[1]);
+    if let Some(limit) = parts.get(2) {
+        rule.limit = parse(limit);
+    }
+}
+
+/// Command line after the `--flag value` pairs have been split off.
+struct Args {
+    positional: Vec<String>,
+    options: ScanOptions,
+    out_dir: Option<String>,
+    state_file: Option<PathBuf>,
+    interval: f32,
+    camera: Option<String>,
+    replay: Option<PathBuf>,
+    timeout: f32,
+    listen: String,
+    dpi: f32,
+}
+
+fn parse_args(args: &[String]) -> Args {
+    let mut options = ScanOptions::default();
+    let mut positional = Vec::new();
+    let mut out_dir = None;
+    let mut state_file = None;
+    let mut interval = 1.0;
+    let mut camera = None;
+    let mut replay = None;
+    let mut timeout = 30.0;
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut dpi = 150.0;
+    let mut gray_level = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || match iter.next() {
+            Some(value) => value.clone(),
+            None => fail(&format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--bg" => options.background = parse_background(&value()),
+            "--out" => out_dir = Some(value()),
+            "--state" => state_file = Some(PathBuf::from(value())),
+            "--interval" => interval = parse(&value()),
+            "--camera" => camera = Some(value()),
+            "--replay" => replay = Some(PathBuf::from(value())),
+            "--timeout" => timeout = parse(&value()),
+            "--listen" => listen = value(),
+            "--dpi" => dpi = parse(&value()),
+            "--template" => options.template = true,
+            "--paper" => options.paper = Some(parse_paper(&value())),
+            "--background-plate" => options.background_plate = Some(PathBuf::from(value())),
+            "--flat-field" => options.flat_field = Some(parse(&value())),
+            "--key-metric" => options.key_metric = parse(&value()),
+            "--key-distance" => options.key_distance = Some(parse(&value())),
+            "--key-falloff" => options.key_falloff = parse(&value()),
+            "--components" => options.cleanup.components = parse(&value()),
+            "--multi" => options.multiple_fish = true,
+            "--min-fish-area" => options.min_fish_area = parse(&value()),
+            "--keep-holes" => options.cleanup.fill_holes = false,
+            "--open" => options.cleanup.open_radius = parse(&value()),
+            "--close" => options.cleanup.close_radius = parse(&value()),
+            "--size" => options.output_width = parse(&value()),
+            "--aspect" => options.aspect_ratio = parse_aspect(&value()),
+            "--padding" => options.padding = parse(&value()),
+            "--orient" => options.orientation = parse(&value()),
+            "--flip" => options.flip = true,
+            "--height" => options.height_model = parse(&value()),
+            "--normal-strength" => options.normals.strength = parse(&value()),
+            "--detail-weight" => options.normals.detail_weight = parse(&value()),
+            "--detail-blur" => options.normals.detail_blur = parse(&value()),
+            "--normal-convention" => options.normals.convention = parse(&value()),
+            "--gradient" => options.normals.gradient = parse(&value()),
+            "--normals-16bit" => options.normals.sixteen_bit = true,
+            "--despill" => options.despill = true,
+            "--white-balance" => options.white_balance = Some(WhiteBalance { reference: parse_white_reference(&value()), target: None }),
+            "--gray-level" => gray_level = Some(parse(&value())),
+            "--edge-fill" => options.edge_fill = parse(&value()),
+            "--premultiply" => options.premultiplied_alpha = true,
+            "--check" => parse_check(&value(), &mut options.quality),
+            "--debug-dir" => options.debug_dir = Some(PathBuf::from(value())),
+            flag if flag.starts_with("--") => fail(&format!("unknown option: {}", flag)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    if let Some(level) = gray_level {
+        match &mut options.white_balance {
+            Some(balance) => balance.target = Some(level),
+            None => fail("--gray-level needs --white-balance"),
+        }
+    }
+    Args { positional, options, out_dir, state_file, interval, camera, replay, timeout, listen, dpi }
+}
+
+fn scan_single(mut args: Args) {
+    let positional = &args.positional;
+    match positional.len() {
+        1 => (),
+        2 if positional[1] == "auto" => args.options.background = Background::Auto,
+        2 if positional[1] == "plate" => args.options.background = Background::Plate,
+        4 => args.options.background = Background::Color(Rgb([
+            parse(&positional[1]),
+            parse(&positional[2]),
+            parse(&positional[3]),
+        ])),
+        _ => fail("please specify the path to the image file"),
+    }
+    default_flat_field(&mut args.options);
+    let options = &args.options;
+    let image_file = &positional[0];
+    let skins = match load_fish_skins(image_file, options) {
+        Ok(skins) => skins,
+        Err(err) => {
+            eprintln!("{}: {}", image_file, err);
+            process::exit(1);
+        }
+    };
+    if let Some(key) = skins.iter().find_map(|skin| skin.as_ref().ok().and_then(|skin| skin.key)) {
+        println!("background color: {} {} {}, key distance: {:.1}", key.color[0], key.color[1], key.color[2], key.distance);
+        match (options.background, &options.background_plate) {
+            (Background::Plate, Some(plate)) => print!("reuse with: scanner {} plate --background-plate {}", image_file, plate.display()),
+            _ => print!("reuse with: scanner {} {} {} {}", image_file, key.color[0], key.color[1], key.color[2]),
+        }
+        print!(" --key-metric {} --key-distance {:.1}", options.key_metric, key.distance);
+        if options.key_falloff > 0.0 {
+            print!(" --key-falloff {}", options.key_falloff);
+        }
+        println!();
+    }
+    let mut failed = false;
+    for (i, skin) in skins.into_iter().enumerate() {
+        // several fish are told apart by their number: `<photo>_1_colors.png`, ...
+        let prefix = if options.multiple_fish {
+            format!("{}_{}", image_file, i + 1)
+        } else {
+            image_file.to_string()
+        };
+        let saved = skin.and_then(|skin| {
+            for warning in skin.quality.warnings() {
+                println!("{}: warning: {}", prefix, warning.message);
+            }
+            save_next_to_photo(&skin, image_file, &prefix)
+        });
+        match saved {
+            Ok(()) if options.multiple_fish => println!("{}_colors.png", prefix),
+            Ok(()) => (),
+            Err(err) => {
+                eprintln!("{}: {}", prefix, err);
+                // what the checks measured helps to take a better photo
+                if let ScanError::Rejected(report) = &err {
+                    if let Err(err) = report.save(format!("{}_quality.json", prefix)) {
+                        eprintln!("{}: {}", prefix, err);
                     }
+                }
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// Saves textures, manifest and quality report as `<prefix>_colors.png`, ... next to the photo.
+fn save_next_to_photo(skin: &FishSkin, image_file: &str, prefix: &str) -> Result<(), ScanError> {
+    // the manifest refers to the textures relative to itself, they're all next to the photo
+    let name = Path::new(prefix).file_name().unwrap_or_default().to_string_lossy();
+    let mut manifest = FishManifest::new(skin, Some(image_file.as_ref()));
+    manifest.colors = format!("{}_colors.png", name);
+    manifest.normals = format!("{}_normals.png", name);
+    skin.save(format!("{}_colors.png", prefix), format!("{}_normals.png", prefix))?;
+    skin.quality.save(format!("{}_quality.json", prefix))?;
+    manifest.save(format!("{}_fish.json", prefix))
+}
+
+fn print_entry(entry: &BatchEntry) {
+    match &entry.result {
+        Ok(number) => println!("{} -> {:04}", entry.input.display(), number),
+        Err(err) => println!("{} failed: {}", entry.input.display(), err),
+    }
+    for warning in &entry.warnings {
+        println!("{} warning: {}", entry.input.display(), warning);
+    }
+}
+
+/// input and output directory of batch, watch, capture and styler mode
+fn directories(args: &Args) -> (&str, &str) {
+    if args.positional.len() != 2 {
+        fail("please specify the input directory");
+    }
+    match &args.out_dir {
+        Some(dir) => (&args.positional[1], dir),
+        None => fail(&format!("{} mode needs --out <dir>", args.positional[0])),
+    }
+}
+
+fn scan_batch(mut args: Args) {
+    default_flat_field(&mut args.options);
+    let (in_dir, out_dir) = directories(&args);
+    let entries = scan_directory(in_dir, out_dir, &args.options, print_entry);
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let failures = entries.iter().filter(|entry| entry.result.is_err()).count();
+    let width = entries.iter().map(|entry| entry.input.display().to_string().len()).max().unwrap_or(0).max(5);
+    println!();
+    println!("{:<width$}  result", "input", width = width);
+    for entry in &entries {
+        let result = match &entry.result {
+            Ok(number) if !entry.warnings.is_empty() => format!("warn  {:04}  {}", number, entry.warnings.join(", ")),
+            Ok(number) => format!("ok    {:04}", number),
+            Err(err) => format!("FAIL  {}", err),
+        };
+        println!("{:<width$}  {}", entry.input.display(), result, width = width);
+    }
+    println!();
+    println!("{} scanned, {} failed", entries.len() - failures, failures);
+    if failures > 0 {
+        process::exit(1);
+    }
+}
 
+fn watch(mut args: Args) {
+    default_flat_field(&mut args.options);
+    let (in_dir, out_dir) = directories(&args);
+    let result = Watcher::new(in_dir, out_dir, args.state_file.clone()).and_then(|mut watcher| {
+        println!("watching {} for new photos, publishing to {}", in_dir, out_dir);
+        watcher.run(&args.options, Duration::from_secs_f32(args.interval), print_entry)
+    });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
 
-                    let center:[u32; 2] = [(l + r) / 2, (t + b) / 2]; 
-                    let mut side = (r - l).max(b - t) as i32 + 20;
-                    side += side % 2; 
-                    let square_l = center[0] as i32 - side / 2;
-                    let square_t = center[1] as i32 - side / 2;
-
-                    println!("image center: {:?}", center); 
-                    println!("side: {:?}", side); 
-                    
-                    let square_mask = ImageBuffer::from_fn(side as u32, side as u32, |x, y| {
-                        let origx = x as i32 + square_l;
-                        let origy = y as i32 + square_t;
-                        if origx < 0 || origy < 0 || origx >= dim.0 as i32 || origy >= dim.1 as i32 {
-                            Luma([0])
-                        } else {
-                            blurred_mask.get_pixel(origx as u32, origy as u32).clone()
-                        }
-                    });
-                    
-                    let square_img = ImageBuffer::from_fn(side as u32, side as u32, |x, y| {
-                        let origx = x as i32 + square_l; 
-                        let origy = y as i32 + square_t; 
-                        if origx < 0 || origy < 0 || origx >= dim.0 as i32 || origy >= dim.1 as i32 {
-                            DEFAULT_BG_COLOR
-                        } else {
-                            img.get_pixel(origx as u32, origy as u32).clone()
-                        }
-                    });
-
-                    // crop mask to square
-                    // - scale to 1024
-                    // - scale to 256 x 128
-                    //   - blur 
-                    //   - unscale
-
-                    let square_mask_1024 = imageops::resize(&square_mask, 1024, 1024, FilterType::CatmullRom);
-                    let square_img_1024 = imageops::resize(&square_img, 1024, 1024, FilterType::CatmullRom); 
-                    let grey_img_1024 = imageops::colorops::grayscale(&square_img_1024);
-
-                    let downsample_size = 128; 
-                    let blur_radius = 16;
-                    let downsampled_mask = imageops::resize(&square_mask_1024, downsample_size, downsample_size, FilterType::CatmullRom); 
-                    let downsample_size_with_border = downsample_size + blur_radius * 2;
-                    let downsampled_mask_with_border = ImageBuffer::from_fn(downsample_size_with_border, downsample_size_with_border, |x, y| {
-                        if x >= blur_radius && x < blur_radius + downsample_size && y >= blur_radius && y < downsample_size + blur_radius {
-                            downsampled_mask.get_pixel(x - blur_radius, y - blur_radius).clone()
-                        } else {
-                            Luma([0])
-                        }
-                    });
-                    let blurred_downsampled_mask = imageops::blur(&downsampled_mask_with_border, blur_radius as f32);
-                    let cropped_back = ImageBuffer::from_fn(downsample_size, downsample_size, |x, y| {
-                        blurred_downsampled_mask.get_pixel(x + blur_radius, y + blur_radius).clone()
-                    });
-                    let heightmap = imageops::resize(&cropped_back, 1024, 1024, FilterType::CatmullRom);
-
-                    let soft_surface = imageops::blur(&grey_img_1024, 2.0); 
-                    let heightmap_with_surface = ImageBuffer::from_fn(1024, 1024, |x, y| {
-                        let height = heightmap.get_pixel(x,y)[0] as u32; 
-                        let surface = soft_surface.get_pixel(x,y)[0] as u32 * (std::u16::MAX as u32 / std::u8::MAX as u32); 
-                        Luma([ ( (height * 19 + surface * 1) / 20) as u16 ])
-                    }); 
-
-                    let normalmap = ImageBuffer::from_fn(1024, 1024, |x, y| {
-                        let pxh =heightmap_with_surface.get_pixel(x,y)[0] as f32 / std::u16::MAX as f32; 
-                        let rh = heightmap_with_surface.get_pixel(x.min(1022) + 1,y)[0] as f32 / std::u16::MAX as f32; 
-                        let bh = heightmap_with_surface.get_pixel(x,y.min(1022) + 1)[0] as f32 / std::u16::MAX as f32;  
-                        // ohne Unterschied ist der Vektor (0,0,-1)
-                        let v = [pxh - rh, pxh - bh, 0.005]; 
-                        let l = (v[0].powi(2) + v[1].powi(2) + v[2].powi(2)).sqrt();
-                        let normalize = |c:f32| {
-                            ((c / l + 1.0) * 0.5 * std::u8::MAX as f32) as u8
-                        };
-                        Rgb([normalize(v[0]),normalize(v[1]),normalize(v[2])])
-                    });
-
-                    let colors = ImageBuffer::from_fn(1024, 1024, |x, y| {
-                        let color = square_img_1024.get_pixel(x,y); 
-                        let alpha = square_mask_1024.get_pixel(x,y); 
-                        Rgba([
-                             color[0],
-                             color[1],
-                             color[2],
-                             (alpha[0] / (std::u16::MAX as u16 / std::u8::MAX as u16)) as u8
-                        ])
-                    });
-
-                    // crop colors to square
-                    // - greyscale
-                    // - add to heightmap
-
-                    // image.crop(
-                    //     center[0] - half_side, 
-                    //     center[1] - half_side, 
-                    //     2 * half_side,
-                    //     2 * half_side
-                    // );
-
-                    // heightmap to normal map
-
-                    Ok((colors, normalmap))
-                },
-                _ => panic!("need rgb8 image")
+/// A folder with `--replay`, gphoto2 otherwise.
+fn capture_source(args: &Args) -> Box<dyn CaptureSource> {
+    match &args.replay {
+        Some(dir) => match Replay::new(dir) {
+            Ok(replay) => Box::new(replay),
+            Err(err) => {
+                eprintln!("{}: {}", dir.display(), err);
+                process::exit(1);
             }
         },
-        Err(err) => panic!(err)
+        None => Box::new(Gphoto2::new(args.camera.clone())),
+    }
+}
+
+/// Takes a photo whenever enter is pressed, then scans and publishes it.
+fn capture(mut args: Args) {
+    default_flat_field(&mut args.options);
+    let (photo_dir, out_dir) = directories(&args);
+    let (photo_dir, out_dir) = (Path::new(photo_dir), Path::new(out_dir));
+    let mut source = capture_source(&args);
+    if let Err(err) = fs::create_dir_all(out_dir) {
+        eprintln!("{}: {}", out_dir.display(), err);
+        process::exit(1);
+    }
+    let timeout = Duration::from_secs_f32(args.timeout);
+    println!("press enter to take a photo, ctrl-d to stop");
+    for _ in io::stdin().lock().lines() {
+        let photo = match source.capture(photo_dir, timeout) {
+            Ok(photo) => photo,
+            Err(CaptureError::Exhausted) => {
+                println!("{}", CaptureError::Exhausted);
+                break;
+            }
+            // the camera may be back on the next try
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let entries = next_fish_number(out_dir).map(|mut number| scan_photo(&photo, &args.options, out_dir, &mut number));
+        match entries {
+            Ok(entries) => entries.iter().for_each(print_entry),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Serves the fish styler web app, with a camera only if `--camera` or `--replay` says which.
+fn styler(mut args: Args) {
+    default_flat_field(&mut args.options);
+    let (photo_dir, out_dir) = directories(&args);
+    let source = match (&args.camera, &args.replay) {
+        (None, None) => None,
+        _ => Some(capture_source(&args)),
+    };
+    let result = Styler::new(photo_dir, out_dir, args.options.clone(), source).and_then(|mut styler| {
+        styler.timeout = Duration::from_secs_f32(args.timeout);
+        println!("fish styler on http://{}/, publishing to {}", args.listen, out_dir);
+        styler.serve(&args.listen)
+    });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn write_template(args: Args) {
+    if args.positional.len() != 2 {
+        fail("please specify where to write the coloring page");
+    }
+    let path = &args.positional[1];
+    if let Err(err) = template::save(path, args.dpi) {
+        eprintln!("{}: {}", path, err);
+        process::exit(1);
     }
 }
 
-pub fn rgb_distance_squared(c1: &Rgb<u8>, c2: &Rgb<u8>) -> u32 {
-    let mut sum:u32 = 0; 
-    for i in 0..2 {
-        sum += (c1[i] as i32 - c2[i] as i32).pow(2) as u32
+pub fn main() {
+    let args = parse_args(&env::args().skip(1).collect::<Vec<_>>());
+    match args.positional.first().map(|s| s.as_str()) {
+        Some("batch") => scan_batch(args),
+        Some("watch") => watch(args),
+        Some("capture") => capture(args),
+        Some("styler") => styler(args),
+        Some("template") => write_template(args),
+        Some(_) => scan_single(args),
+        None => fail("please specify the path to the image file"),
     }
-    sum
-}
\ No newline at end of file
+}
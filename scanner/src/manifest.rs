@@ -0,0 +1,141 @@
+//! `NNNN-fish.json`, the sidecar that describes a species next to its two textures.
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::{
+    crop::CropRect,
+    error::ScanError,
+    morphology::BoundingBox,
+    orientation::Orientation,
+    palette::{
+        dominant_colors,
+        DominantColor,
+    },
+    FishSkin,
+};
+
+use std::{
+    fs,
+    path::Path,
+};
+
+/// Bumped whenever a field changes meaning or is removed. Adding fields doesn't bump it.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// From the ideas file: the surface a species covers in the tank should be the same for
+/// every species, so `size² * amount` is constant. Tuned so that a fish covering 40% of its
+/// texture at scale 1 gets a school of 250.
+pub const SURFACE_CONSTANT: f32 = 100.0;
+const MAX_SCHOOL_SIZE: u32 = 1024;
+const DOMINANT_COLOR_COUNT: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FishManifest {
+    pub version: u32,
+    /// texture file names, relative to the manifest
+    pub colors: String,
+    pub normals: String,
+    /// the photo the skin was made from
+    pub source: Option<String>,
+    /// where the fish was found in the photo
+    pub bounding_box: BoundingBox,
+    /// rotation and mirroring applied to get the fish level with its head to the right
+    #[serde(default)]
+    pub orientation: Orientation,
+    /// the part of the (turned) photo that ended up in the textures
+    pub crop: CropRect,
+    /// the background color that was keyed out, `null` if the photo had an alpha channel
+    pub key_color: Option<[u8; 3]>,
+    /// the color channels of the colors texture are multiplied by alpha
+    #[serde(default)]
+    pub premultiplied_alpha: bool,
+    pub dominant_colors: Vec<DominantColor>,
+    /// fraction of the texture covered by the fish
+    pub silhouette_area: f32,
+    pub appearance: Appearance,
+    pub behaviour: Behaviour,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Appearance {
+    /// length, height and width of the fish relative to the default fish size
+    pub scale: [f32; 3],
+    /// `size² * school_size`, see `SURFACE_CONSTANT`
+    pub surface_constant: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Behaviour {
+    /// how strongly a fish follows its school, 0 = loner, 1 = tight school
+    pub swarm_factor: f32,
+    /// how many fish of this species swim in the tank
+    pub school_size: u32,
+}
+
+impl Default for Appearance {
+    fn default() -> Self {
+        Appearance {
+            scale: [1.0, 1.0, 1.0],
+            surface_constant: SURFACE_CONSTANT,
+        }
+    }
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Behaviour {
+            swarm_factor: 0.5,
+            school_size: 256,
+        }
+    }
+}
+
+impl FishManifest {
+    /// Describes `skin` with default appearance and behaviour. The texture names are
+    /// filled in by `output::publish`.
+    pub fn new(skin: &FishSkin, source: Option<&Path>) -> FishManifest {
+        let (width, height) = skin.colors.dimensions();
+        let coverage: u64 = skin.colors.pixels().map(|p| p[3] as u64).sum();
+        let silhouette_area = coverage as f32 / (255.0 * width as f32 * height as f32);
+        let mut manifest = FishManifest {
+            version: MANIFEST_VERSION,
+            colors: String::new(),
+            normals: String::new(),
+            source: source.map(|path| path.display().to_string()),
+            bounding_box: skin.bbox,
+            orientation: skin.orientation,
+            crop: skin.crop,
+            key_color: skin.key.map(|key| key.color.0),
+            premultiplied_alpha: skin.premultiplied_alpha,
+            dominant_colors: dominant_colors(&skin.colors, DOMINANT_COLOR_COUNT),
+            silhouette_area,
+            appearance: Appearance::default(),
+            behaviour: Behaviour::default(),
+        };
+        manifest.update_school_size();
+        manifest
+    }
+
+    /// Recomputes `behaviour.school_size` from the silhouette, the scale and the surface constant.
+    /// Call after changing the scale.
+    pub fn update_school_size(&mut self) {
+        let size = self.appearance.scale[0] * self.appearance.scale[1];
+        let surface = (size * self.silhouette_area).max(f32::EPSILON);
+        let school_size = (self.appearance.surface_constant / surface).round() as u32;
+        self.behaviour.school_size = school_size.clamp(1, MAX_SCHOOL_SIZE);
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<FishManifest, ScanError> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|err| ScanError::Manifest(err.to_string()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ScanError> {
+        let json = serde_json::to_string_pretty(self).map_err(|err| ScanError::Manifest(err.to_string()))?;
+        fs::write(path, json + "\n")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+//! What the camera says about a photo besides its pixels: the EXIF orientation and an
+//! embedded ICC color profile.
+
+use image::{
+    DynamicImage,
+    ImageBuffer,
+    Rgb,
+    Rgba,
+};
+
+use qcms::{
+    DataType,
+    Intent,
+    Profile,
+    Transform,
+};
+
+use std::io::Cursor;
+
+/// grid points per channel of the lookup table that converts 16 bit photos,
+/// 17 steps of 15 hit 8 bit values exactly
+const LUT_SIZE: usize = 18;
+/// the tiff tag holding an ICC profile
+const ICC_PROFILE_TAG: usize = 34675;
+
+/// The EXIF orientation of a jpeg, png, webp or tiff based (e.g. raw) file: 1 is upright,
+/// 2 to 8 are the mirrored and turned variants. `None` if the file doesn't say.
+pub fn exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(data)).ok()?;
+    let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    field.value.get_uint(0).filter(|o| (1..=8).contains(o))
+}
+
+/// Turns `image` upright according to its EXIF orientation.
+pub fn orient(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// The ICC profile embedded in a jpeg (APP2 segments), png (iCCP chunk), webp (ICCP chunk)
+/// or tiff (InterColorProfile tag).
+pub fn icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_profile(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_profile(data)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        webp_profile(data)
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        tiff_profile(data)
+    } else {
+        None
+    }
+}
+
+/// A profile can be split over several APP2 segments, each numbered.
+fn jpeg_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        // start of scan: no more metadata after this
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let length = ((data[pos + 2] as usize) << 8) | data[pos + 3] as usize;
+        let segment = data.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE2 && segment.starts_with(b"ICC_PROFILE\0") && segment.len() > 14 {
+            chunks.push((segment[12], &segment[14..]));
+        }
+        pos += 2 + length;
+    }
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(number, _)| *number);
+    Some(chunks.iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+fn png_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 8;
+    while pos + 8 <= data.len() {
+        let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let chunk = data.get(pos + 8..pos + 8 + length)?;
+        match kind {
+            // name, a zero byte, the compression method and the zlib stream
+            b"iCCP" => {
+                let name_end = chunk.iter().position(|&b| b == 0)?;
+                return miniz_oxide::inflate::decompress_to_vec_zlib(chunk.get(name_end + 2..)?).ok();
+            }
+            b"IDAT" | b"IEND" => return None,
+            _ => (),
+        }
+        pos += 12 + length;
+    }
+    None
+}
+
+fn webp_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let length = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        if &data[pos..pos + 4] == b"ICCP" {
+            return data.get(pos + 8..pos + 8 + length).map(|chunk| chunk.to_vec());
+        }
+        // chunks are padded to an even length
+        pos += 8 + length + length % 2;
+    }
+    None
+}
+
+/// The profile of the first image of the file, scanners write one per page at most.
+fn tiff_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let big_endian = data[0] == b'M';
+    let u16_at = |pos: usize| {
+        let b = data.get(pos..pos + 2)?;
+        Some(if big_endian { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) } as usize)
+    };
+    let u32_at = |pos: usize| {
+        let b = data.get(pos..pos + 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) } as usize)
+    };
+    let ifd = u32_at(4)?;
+    for i in 0..u16_at(ifd)? {
+        let entry = ifd + 2 + i * 12;
+        if u16_at(entry)? == ICC_PROFILE_TAG {
+            // bytes, stored elsewhere in the file unless they fit into the entry
+            let length = u32_at(entry + 4)?;
+            let offset = if length <= 4 { entry + 8 } else { u32_at(entry + 8)? };
+            return data.get(offset..offset + length).map(|profile| profile.to_vec());
+        }
+    }
+    None
+}
+
+/// Converts `image` from the color space described by `icc` to sRGB, the color space `sea`
+/// assumes for the colors texture. Images that are sRGB already, grayscale images and
+/// profiles that aren't rgb or can't be read are left as they are.
+pub fn to_srgb(image: DynamicImage, icc: &[u8]) -> DynamicImage {
+    // the color space of the profile's device side, gray and cmyk profiles don't fit rgb pixels
+    if icc.get(16..20) != Some(b"RGB ") {
+        return image;
+    }
+    let profile = match Profile::new_from_slice(icc, false) {
+        Some(profile) if !profile.is_sRGB() => profile,
+        _ => return image,
+    };
+    let mut srgb = Profile::new_sRGB();
+    srgb.precache_output_transform();
+    let transform = |kind| Transform::new(&profile, &srgb, kind, Intent::default());
+    match image {
+        DynamicImage::ImageRgb8(mut img) => match transform(DataType::RGB8) {
+            Some(transform) => {
+                transform.apply(&mut img);
+                DynamicImage::ImageRgb8(img)
+            }
+            None => DynamicImage::ImageRgb8(img),
+        },
+        DynamicImage::ImageRgba8(mut img) => match transform(DataType::RGBA8) {
+            Some(transform) => {
+                transform.apply(&mut img);
+                DynamicImage::ImageRgba8(img)
+            }
+            None => DynamicImage::ImageRgba8(img),
+        },
+        DynamicImage::ImageRgb16(img) => match transform(DataType::RGB8) {
+            Some(transform) => {
+                let lut = Lut::new(&transform);
+                let (width, height) = img.dimensions();
+                DynamicImage::ImageRgb16(ImageBuffer::from_fn(width, height, |x, y| Rgb(lut.apply(&img.get_pixel(x, y).0))))
+            }
+            None => DynamicImage::ImageRgb16(img),
+        },
+        DynamicImage::ImageRgba16(img) => match transform(DataType::RGB8) {
+            Some(transform) => {
+                let lut = Lut::new(&transform);
+                let (width, height) = img.dimensions();
+                DynamicImage::ImageRgba16(ImageBuffer::from_fn(width, height, |x, y| {
+                    let p = img.get_pixel(x, y);
+                    let [r, g, b] = lut.apply(&[p[0], p[1], p[2]]);
+                    Rgba([r, g, b, p[3]])
+                }))
+            }
+            None => DynamicImage::ImageRgba16(img),
+        },
+        image => image,
+    }
+}
+
+/// The transform sampled on a grid, for 16 bit pixels: converting them through 8 bits would
+/// cost the precision the normal map wants them for.
+struct Lut {
+    /// converted colors, 0 to 1, red varying fastest
+    values: Vec<[f32; 3]>,
+}
+
+impl Lut {
+    fn new(transform: &Transform) -> Lut {
+        let level = |i: usize| (i * 255 / (LUT_SIZE - 1)) as u8;
+        let mut grid = Vec::with_capacity(LUT_SIZE * LUT_SIZE * LUT_SIZE * 3);
+        for b in 0..LUT_SIZE {
+            for g in 0..LUT_SIZE {
+                for r in 0..LUT_SIZE {
+                    grid.extend_from_slice(&[level(r), level(g), level(b)]);
+                }
+            }
+        }
+        transform.apply(&mut grid);
+        Lut { values: grid.chunks(3).map(|c| [c[0], c[1], c[2]].map(|v| v as f32 / 255.0)).collect() }
+    }
+
+    /// trilinear interpolation between the grid points around `rgb`
+    fn apply(&self, rgb: &[u16; 3]) -> [u16; 3] {
+        let position = rgb.map(|c| c as f32 / u16::MAX as f32 * (LUT_SIZE - 1) as f32);
+        let low = position.map(|p| (p as usize).min(LUT_SIZE - 2));
+        let t = [0, 1, 2].map(|c| position[c] - low[c] as f32);
+        let mut out = [0f32; 3];
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let weight: f32 = (0..3).map(|c| if offset[c] == 1 { t[c] } else { 1.0 - t[c] }).product();
+            let index = ((low[2] + offset[2]) * LUT_SIZE + low[1] + offset[1]) * LUT_SIZE + low[0] + offset[0];
+            for (o, v) in out.iter_mut().zip(self.values[index].iter()) {
+                *o += weight * v;
+            }
+        }
+        out.map(|v| (v.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a tiff header and a first ifd with just the profile tag, no image data
+    fn tiff(big_endian: bool, profile: &[u8]) -> Vec<u8> {
+        let u16_bytes = |v: u16| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+        let u32_bytes = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+        let mut data = if big_endian { b"MM\0*".to_vec() } else { b"II*\0".to_vec() };
+        data.extend_from_slice(&u32_bytes(8));
+        data.extend_from_slice(&u16_bytes(1));
+        data.extend_from_slice(&u16_bytes(ICC_PROFILE_TAG as u16));
+        data.extend_from_slice(&u16_bytes(7));
+        data.extend_from_slice(&u32_bytes(profile.len() as u32));
+        if profile.len() <= 4 {
+            let mut inline = profile.to_vec();
+            inline.resize(4, 0);
+            data.extend_from_slice(&inline);
+        } else {
+            data.extend_from_slice(&u32_bytes(26));
+        }
+        data.extend_from_slice(&u32_bytes(0));
+        if profile.len() > 4 {
+            data.extend_from_slice(profile);
+        }
+        data
+    }
+
+    #[test]
+    fn reads_the_tiff_profile_tag() {
+        let profile = b"not really a profile";
+        assert_eq!(icc_profile(&tiff(false, profile)).as_deref(), Some(&profile[..]));
+        assert_eq!(icc_profile(&tiff(true, profile)).as_deref(), Some(&profile[..]));
+        assert_eq!(icc_profile(&tiff(false, b"abc")).as_deref(), Some(&b"abc"[..]));
+        // cut off in the middle of the profile
+        assert_eq!(icc_profile(&tiff(false, profile)[..30]), None);
+    }
+}
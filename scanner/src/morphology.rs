@@ -0,0 +1,447 @@
+//! Binary mask cleanup: connected components, hole filling, opening and closing.
+
+use image::{
+    ImageBuffer,
+    Luma,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::input::MaskImage;
+
+use std::str::FromStr;
+
+/// A black and white mask, `true` is fish.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<bool>,
+}
+
+/// Inclusive pixel bounds of a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl BoundingBox {
+    pub fn width(&self) -> u32 {
+        self.right - self.left + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        self.bottom - self.top + 1
+    }
+
+    pub fn overlaps(&self, other: &BoundingBox) -> bool {
+        self.left <= other.right && other.left <= self.right && self.top <= other.bottom && other.top <= self.bottom
+    }
+
+    fn include(&mut self, x: u32, y: u32) {
+        self.left = self.left.min(x);
+        self.right = self.right.max(x);
+        self.top = self.top.min(y);
+        self.bottom = self.bottom.max(y);
+    }
+}
+
+/// One 8-connected region of a `Bitmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Component {
+    /// label in the map returned by `Bitmap::components`, starting at 1
+    pub label: u32,
+    pub area: u32,
+    pub bbox: BoundingBox,
+}
+
+/// Which connected components survive the cleanup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComponentFilter {
+    /// keep everything, no cleanup
+    All,
+    /// keep only the biggest region, i.e. the fish
+    Largest,
+    /// keep every region covering at least this fraction of the image
+    MinArea(f32),
+}
+
+impl FromStr for ComponentFilter {
+    type Err = String;
+
+    /// `all`, `largest` or a minimum area as fraction of the image, e.g. `0.01`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(ComponentFilter::All),
+            "largest" => Ok(ComponentFilter::Largest),
+            _ => s.parse().map(ComponentFilter::MinArea)
+                .map_err(|_| format!("unknown component filter: {} (all, largest or a minimum area fraction)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaskCleanup {
+    pub components: ComponentFilter,
+    /// turn background enclosed by fish (e.g. white spots keyed as backdrop) into fish
+    pub fill_holes: bool,
+    /// radius of the opening (erode, then dilate) that removes thin strands and specks
+    pub open_radius: u32,
+    /// radius of the closing (dilate, then erode) that bridges small gaps
+    pub close_radius: u32,
+}
+
+impl Default for MaskCleanup {
+    fn default() -> Self {
+        MaskCleanup {
+            components: ComponentFilter::Largest,
+            fill_holes: true,
+            open_radius: 0,
+            close_radius: 0,
+        }
+    }
+}
+
+impl Bitmap {
+    pub fn new(width: u32, height: u32) -> Bitmap {
+        Bitmap {
+            width,
+            height,
+            data: vec![false; (width * height) as usize],
+        }
+    }
+
+    /// every pixel at or above `threshold` is set
+    pub fn from_mask(mask: &MaskImage, threshold: u16) -> Bitmap {
+        Bitmap {
+            width: mask.width(),
+            height: mask.height(),
+            data: mask.pixels().map(|p| p[0] >= threshold).collect(),
+        }
+    }
+
+    pub fn to_mask(&self) -> MaskImage {
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            Luma([if self.get(x, y) { u16::MAX } else { 0 }])
+        })
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, value: bool) {
+        self.data[(y * self.width + x) as usize] = value;
+    }
+
+    pub fn area(&self) -> u32 {
+        self.data.iter().filter(|&&set| set).count() as u32
+    }
+
+    /// bounds of all set pixels, `None` if the bitmap is empty
+    pub fn bounding_box(&self) -> Option<BoundingBox> {
+        let mut bbox: Option<BoundingBox> = None;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.get(x, y) {
+                    match &mut bbox {
+                        Some(bbox) => bbox.include(x, y),
+                        None => bbox = Some(BoundingBox { left: x, top: y, right: x, bottom: y }),
+                    }
+                }
+            }
+        }
+        bbox
+    }
+
+    /// Labels the 8-connected regions. Returns the label of every pixel (0 = not set)
+    /// and the components, ordered by label.
+    pub fn components(&self) -> (Vec<u32>, Vec<Component>) {
+        let mut labels = vec![0u32; self.data.len()];
+        let mut components = Vec::new();
+        let mut stack = Vec::new();
+        for start in 0..self.data.len() {
+            if !self.data[start] || labels[start] != 0 {
+                continue;
+            }
+            let label = components.len() as u32 + 1;
+            let (sx, sy) = (start as u32 % self.width, start as u32 / self.width);
+            let mut component = Component {
+                label,
+                area: 0,
+                bbox: BoundingBox { left: sx, top: sy, right: sx, bottom: sy },
+            };
+            labels[start] = label;
+            stack.push(start);
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i as u32 % self.width, i as u32 / self.width);
+                component.area += 1;
+                component.bbox.include(x, y);
+                for (nx, ny) in self.neighbours(x, y) {
+                    let n = (ny * self.width + nx) as usize;
+                    if self.data[n] && labels[n] == 0 {
+                        labels[n] = label;
+                        stack.push(n);
+                    }
+                }
+            }
+            components.push(component);
+        }
+        (labels, components)
+    }
+
+    fn neighbours(&self, x: u32, y: u32) -> impl Iterator<Item = (u32, u32)> {
+        let (width, height) = (self.width as i64, self.height as i64);
+        let (x, y) = (x as i64, y as i64);
+        (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(move |&(nx, ny)| (nx, ny) != (x, y) && nx >= 0 && ny >= 0 && nx < width && ny < height)
+            .map(|(nx, ny)| (nx as u32, ny as u32))
+    }
+
+    pub fn filter_components(&self, filter: ComponentFilter) -> Bitmap {
+        if filter == ComponentFilter::All {
+            return self.clone();
+        }
+        let (labels, components) = self.components();
+        let keep: Vec<bool> = match filter {
+            ComponentFilter::All => vec![true; components.len()],
+            ComponentFilter::Largest => {
+                let largest = components.iter().max_by_key(|c| c.area).map(|c| c.label);
+                components.iter().map(|c| Some(c.label) == largest).collect()
+            }
+            ComponentFilter::MinArea(fraction) => {
+                let min_area = (self.width * self.height) as f32 * fraction;
+                components.iter().map(|c| c.area as f32 >= min_area).collect()
+            }
+        };
+        Bitmap {
+            width: self.width,
+            height: self.height,
+            data: labels.iter().map(|&label| label != 0 && keep[label as usize - 1]).collect(),
+        }
+    }
+
+    /// Splits the bitmap into one bitmap per component, in reading order: rows from top to
+    /// bottom, left to right within a row. A component belongs to the row of the topmost
+    /// remaining one if its middle is above that one's bottom.
+    pub fn regions(&self) -> Vec<Bitmap> {
+        let (labels, mut components) = self.components();
+        components.sort_by_key(|c| (c.bbox.top, c.bbox.left));
+        let mut ordered = Vec::new();
+        while let Some(first) = components.first().map(|c| c.bbox) {
+            let (mut row, rest): (Vec<Component>, Vec<Component>) = components.into_iter()
+                .partition(|c| (c.bbox.top + c.bbox.bottom) / 2 <= first.bottom);
+            row.sort_by_key(|c| (c.bbox.left, c.bbox.top));
+            ordered.extend(row);
+            components = rest;
+        }
+        ordered.iter().map(|c| Bitmap {
+            width: self.width,
+            height: self.height,
+            data: labels.iter().map(|&label| label == c.label).collect(),
+        }).collect()
+    }
+
+    /// Sets every unset pixel that can't be reached from the image border.
+    pub fn fill_holes(&self) -> Bitmap {
+        let mut outside = vec![false; self.data.len()];
+        let mut stack = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let on_border = x == 0 || y == 0 || x == self.width - 1 || y == self.height - 1;
+                let i = (y * self.width + x) as usize;
+                if on_border && !self.data[i] {
+                    outside[i] = true;
+                    stack.push(i);
+                }
+            }
+        }
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i as u32 % self.width, i as u32 / self.width);
+            // 4-connected, so the background can't leak through diagonal gaps in the 8-connected fish
+            let candidates = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            for &(nx, ny) in candidates.iter() {
+                if nx < self.width && ny < self.height {
+                    let n = (ny * self.width + nx) as usize;
+                    if !self.data[n] && !outside[n] {
+                        outside[n] = true;
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+        Bitmap {
+            width: self.width,
+            height: self.height,
+            data: outside.iter().map(|&o| !o).collect(),
+        }
+    }
+
+    /// Grows the set region by `radius` pixels (square structuring element).
+    pub fn dilate(&self, radius: u32) -> Bitmap {
+        self.max_filter(radius, true)
+    }
+
+    /// Shrinks the set region by `radius` pixels (square structuring element).
+    pub fn erode(&self, radius: u32) -> Bitmap {
+        self.max_filter(radius, false)
+    }
+
+    pub fn open(&self, radius: u32) -> Bitmap {
+        self.erode(radius).dilate(radius)
+    }
+
+    pub fn close(&self, radius: u32) -> Bitmap {
+        self.dilate(radius).erode(radius)
+    }
+
+    /// separable running window: a pixel becomes `value` if any pixel in the window has it
+    fn max_filter(&self, radius: u32, value: bool) -> Bitmap {
+        if radius == 0 {
+            return self.clone();
+        }
+        let (width, height) = (self.width as usize, self.height as usize);
+        let r = radius as usize;
+        let pass = |src: &[bool], len: usize, count: usize, index: &dyn Fn(usize, usize) -> usize| {
+            let mut out = src.to_vec();
+            let mut prefix = vec![0u32; len + 1];
+            for line in 0..count {
+                for i in 0..len {
+                    prefix[i + 1] = prefix[i] + (src[index(line, i)] == value) as u32;
+                }
+                for i in 0..len {
+                    let from = i.saturating_sub(r);
+                    let to = (i + r + 1).min(len);
+                    if prefix[to] - prefix[from] > 0 {
+                        out[index(line, i)] = value;
+                    }
+                }
+            }
+            out
+        };
+        let horizontal = pass(&self.data, width, height, &|y, x| y * width + x);
+        let data = pass(&horizontal, height, width, &|x, y| y * width + x);
+        Bitmap {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+
+    /// Applies `cleanup`: opening, closing, component filter and hole filling, in that order.
+    pub fn cleaned(&self, cleanup: &MaskCleanup) -> Bitmap {
+        let mut bitmap = self.open(cleanup.open_radius).close(cleanup.close_radius);
+        bitmap = bitmap.filter_components(cleanup.components);
+        if cleanup.fill_holes {
+            bitmap = bitmap.fill_holes();
+        }
+        bitmap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `#` is set
+    fn bitmap(rows: &[&str]) -> Bitmap {
+        Bitmap {
+            width: rows[0].len() as u32,
+            height: rows.len() as u32,
+            data: rows.iter().flat_map(|row| row.chars().map(|c| c == '#')).collect(),
+        }
+    }
+
+    #[test]
+    fn ring_is_filled() {
+        let ring = bitmap(&[
+            ".......",
+            ".#####.",
+            ".#...#.",
+            ".#...#.",
+            ".#####.",
+            ".......",
+        ]);
+        assert_eq!(ring.fill_holes(), bitmap(&[
+            ".......",
+            ".#####.",
+            ".#####.",
+            ".#####.",
+            ".#####.",
+            ".......",
+        ]));
+        // the background is 4-connected, it doesn't leak through the corners of a diamond
+        let diamond = bitmap(&[
+            "..#..",
+            ".#.#.",
+            "#...#",
+            ".#.#.",
+            "..#..",
+        ]);
+        assert!(diamond.fill_holes().get(2, 2));
+        assert_eq!(diamond.fill_holes().area(), 13);
+        // a hole open to the border isn't one
+        let open = bitmap(&[
+            "#.#",
+            "#.#",
+            "###",
+        ]);
+        assert_eq!(open.fill_holes(), open);
+    }
+
+    #[test]
+    fn diagonal_touch_is_connected() {
+        let (labels, components) = bitmap(&[
+            "#..",
+            ".#.",
+            "..#",
+        ]).components();
+        assert_eq!(components.len(), 1);
+        assert_eq!(components[0].area, 3);
+        assert_eq!(labels, [1, 0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    /// three blobs, the one on the left starts a row lower than the one on the right
+    fn blobs() -> Bitmap {
+        bitmap(&[
+            "......##",
+            "##....##",
+            "##......",
+            "........",
+            "........",
+            "......##",
+        ])
+    }
+
+    #[test]
+    fn blobs_in_scan_order() {
+        // labels follow the scan, top first
+        let (_, components) = blobs().components();
+        let summary: Vec<(u32, u32, u32, u32)> = components.iter().map(|c| (c.label, c.area, c.bbox.left, c.bbox.top)).collect();
+        assert_eq!(summary, [(1, 4, 6, 0), (2, 4, 0, 1), (3, 2, 6, 5)]);
+    }
+
+    #[test]
+    fn regions_in_reading_order() {
+        let blobs = blobs();
+        let regions = blobs.regions();
+        // a row is read left to right: the left blob starts lower, but its middle is in the first row
+        let corners: Vec<(u32, u32)> = regions.iter().map(|region| {
+            let bbox = region.bounding_box().unwrap();
+            (bbox.left, bbox.top)
+        }).collect();
+        assert_eq!(corners, [(0, 1), (6, 0), (6, 5)]);
+        assert_eq!(regions.iter().map(Bitmap::area).sum::<u32>(), blobs.area());
+    }
+}
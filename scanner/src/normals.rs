@@ -0,0 +1,205 @@
+//! From heightmap to normal map.
+
+use image::{
+    imageops,
+    DynamicImage,
+    ImageBuffer,
+    Luma,
+    Rgb,
+};
+
+use crate::{
+    debug::DebugDump,
+    error::ScanError,
+    input::MaskImage,
+};
+
+use std::str::FromStr;
+
+/// Which way the green channel points. The original scanner (and `sea`) use DirectX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalConvention {
+    /// Y+, green points up in the texture
+    OpenGl,
+    /// Y-, green points down in the texture
+    DirectX,
+}
+
+impl FromStr for NormalConvention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opengl" | "gl" => Ok(NormalConvention::OpenGl),
+            "directx" | "dx" => Ok(NormalConvention::DirectX),
+            _ => Err(format!("unknown normal map convention: {} (opengl, directx)", s)),
+        }
+    }
+}
+
+/// How the slope of the heightmap is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gradient {
+    /// difference to the right and lower neighbour, what the original scanner did.
+    /// Shifts every edge by half a pixel.
+    Forward,
+    /// difference between the left and right (upper and lower) neighbour
+    Central,
+    /// central difference smoothed across the gradient, the least noisy
+    Sobel,
+}
+
+impl FromStr for Gradient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(Gradient::Forward),
+            "central" => Ok(Gradient::Central),
+            "sobel" => Ok(Gradient::Sobel),
+            _ => Err(format!("unknown gradient: {} (forward, central, sobel)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NormalOptions {
+    /// steepness of the normals, 1 is the original scanner's look
+    pub strength: f32,
+    /// share of the painted surface detail in the heightmap, the rest is the body bulge
+    pub detail_weight: f32,
+    /// blur radius of the surface detail in pixels (at 1024), hides paper grain and pen noise
+    pub detail_blur: f32,
+    pub convention: NormalConvention,
+    pub gradient: Gradient,
+    /// write a 16 bit png, smoother shading on big shallow bulges
+    pub sixteen_bit: bool,
+}
+
+impl Default for NormalOptions {
+    fn default() -> Self {
+        NormalOptions {
+            strength: 1.0,
+            detail_weight: 0.05,
+            detail_blur: 2.0,
+            convention: NormalConvention::DirectX,
+            gradient: Gradient::Forward,
+            sixteen_bit: false,
+        }
+    }
+}
+
+impl NormalOptions {
+    pub fn validate(&self) -> Result<(), ScanError> {
+        if self.strength <= 0.0 {
+            return Err(ScanError::InvalidOption(format!("normal strength {} is not positive", self.strength)));
+        }
+        if !(0.0..=1.0).contains(&self.detail_weight) {
+            return Err(ScanError::InvalidOption(format!("detail weight {} is not in [0, 1]", self.detail_weight)));
+        }
+        if self.detail_blur < 0.0 {
+            return Err(ScanError::InvalidOption(format!("detail blur {} is negative", self.detail_blur)));
+        }
+        Ok(())
+    }
+}
+
+/// Mixes the body bulge with the painted surface detail (`grey`, the brightness of the skin).
+pub fn surface_heightmap(heightmap: &MaskImage, grey: &MaskImage, options: &NormalOptions, scale: f32, debug: &mut DebugDump) -> MaskImage {
+    let radius = options.detail_blur * scale;
+    let soft_surface = if radius > 0.0 {
+        imageops::blur(grey, radius)
+    } else {
+        grey.clone()
+    };
+    debug.add("surface detail", || DynamicImage::ImageLuma16(soft_surface.clone()));
+    let weight = options.detail_weight;
+    ImageBuffer::from_fn(heightmap.width(), heightmap.height(), |x, y| {
+        let height = heightmap.get_pixel(x, y)[0] as f32;
+        let surface = soft_surface.get_pixel(x, y)[0] as f32;
+        Luma([(height * (1.0 - weight) + surface * weight) as u16])
+    })
+}
+
+/// Normal map of `heightmap`, 8 or 16 bit rgb. Red is high where the height rises to the right,
+/// green see `NormalConvention`.
+pub fn normal_map(heightmap: &MaskImage, options: &NormalOptions, scale: f32) -> DynamicImage {
+    let (width, height) = heightmap.dimensions();
+    let h = |x: i64, y: i64| {
+        let x = x.max(0).min(width as i64 - 1) as u32;
+        let y = y.max(0).min(height as i64 - 1) as u32;
+        heightmap.get_pixel(x, y)[0] as f32 / u16::MAX as f32
+    };
+    // height differences between neighbours shrink with resolution
+    let flat = 0.005 / (scale * options.strength);
+    let y_sign = match options.convention {
+        NormalConvention::DirectX => 1.0,
+        NormalConvention::OpenGl => -1.0,
+    };
+    let normal = |x: u32, y: u32| {
+        let (x, y) = (x as i64, y as i64);
+        // downhill to the right and to the bottom
+        let (dx, dy) = match options.gradient {
+            // the last column and row are flat, like in the original scanner
+            Gradient::Forward => (h(x, y) - h(x + 1, y), h(x, y) - h(x, y + 1)),
+            Gradient::Central => (
+                (h(x - 1, y) - h(x + 1, y)) / 2.0,
+                (h(x, y - 1) - h(x, y + 1)) / 2.0,
+            ),
+            Gradient::Sobel => (
+                (h(x - 1, y - 1) + 2.0 * h(x - 1, y) + h(x - 1, y + 1)
+                    - h(x + 1, y - 1) - 2.0 * h(x + 1, y) - h(x + 1, y + 1)) / 8.0,
+                (h(x - 1, y - 1) + 2.0 * h(x, y - 1) + h(x + 1, y - 1)
+                    - h(x - 1, y + 1) - 2.0 * h(x, y + 1) - h(x + 1, y + 1)) / 8.0,
+            ),
+        };
+        // ohne Unterschied ist der Vektor (0,0,-1)
+        // `sea` lays the texture onto the fish mirrored (`uv.x = 1 - p` in fish.gs.glsl),
+        // so red points uphill to the right in the texture
+        let v = [-dx, dy * y_sign, flat];
+        let l = (v[0].powi(2) + v[1].powi(2) + v[2].powi(2)).sqrt();
+        [(v[0] / l + 1.0) * 0.5, (v[1] / l + 1.0) * 0.5, (v[2] / l + 1.0) * 0.5]
+    };
+    if options.sixteen_bit {
+        let quantize = |c: f32| (c * u16::MAX as f32).round() as u16;
+        DynamicImage::ImageRgb16(ImageBuffer::from_fn(width, height, |x, y| {
+            let n = normal(x, y);
+            Rgb([quantize(n[0]), quantize(n[1]), quantize(n[2])])
+        }))
+    } else {
+        let quantize = |c: f32| (c * u8::MAX as f32) as u8;
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            let n = normal(x, y);
+            Rgb([quantize(n[0]), quantize(n[1]), quantize(n[2])])
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a heightmap rising to the right
+    fn ramp() -> MaskImage {
+        ImageBuffer::from_fn(8, 8, |x, _| Luma([x as u16 * 4000]))
+    }
+
+    #[test]
+    fn red_points_uphill() {
+        for gradient in [Gradient::Forward, Gradient::Central, Gradient::Sobel] {
+            let options = NormalOptions { gradient, ..NormalOptions::default() };
+            let normals = normal_map(&ramp(), &options, 1.0).to_rgb8();
+            let middle = normals.get_pixel(4, 4);
+            assert!(middle[0] > 200, "{:?} gave red {}", gradient, middle[0]);
+            // no slope from top to bottom
+            assert!((middle[1] as i32 - 127).abs() <= 1, "{:?} gave green {}", gradient, middle[1]);
+        }
+    }
+
+    #[test]
+    fn forward_edge_is_flat() {
+        let normals = normal_map(&ramp(), &NormalOptions::default(), 1.0).to_rgb8();
+        let last = normals.get_pixel(7, 4);
+        assert_eq!((last[0], last[1], last[2]), (127, 127, 255));
+    }
+}
@@ -0,0 +1,139 @@
+use image::Rgb;
+
+use crate::{
+    correction::WhiteBalance,
+    error::ScanError,
+    fill::EdgeFill,
+    heightmap::HeightModel,
+    keying::KeyMetric,
+    lighting::FlatField,
+    morphology::MaskCleanup,
+    normals::NormalOptions,
+    orientation::OrientMode,
+    perspective::Paper,
+    quality::QualityRules,
+};
+
+use std::path::PathBuf;
+
+pub const DEFAULT_BG_COLOR: Rgb<u8> = Rgb([18,18,18]); // default: almost black
+
+/// Where the backdrop color comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    /// a known color, e.g. picked by the operator
+    Color(Rgb<u8>),
+    /// estimated from the image border
+    Auto,
+    /// no single color: every pixel is compared with the same pixel of `ScanOptions::background_plate`.
+    /// For textured or patterned tables, needs a tripod
+    Plate,
+}
+
+/// Knobs for `load_fish_skin`. `ScanOptions::default()` keys a hard edged mask against an almost black backdrop.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub background: Background,
+    /// a photo of the empty backdrop, for `flat_field` or `Background::Plate`
+    pub background_plate: Option<PathBuf>,
+    /// even out the lighting before keying
+    pub flat_field: Option<FlatField>,
+    pub key_metric: KeyMetric,
+    /// Color distance (in units of `key_metric`) below which a pixel counts as background.
+    /// `None` uses the metric's default for a fixed color and a derived value in auto mode.
+    pub key_distance: Option<f32>,
+    /// Width of the band above `key_distance` in which pixels are partially transparent.
+    /// 0 gives the hard edged mask of the original scanner.
+    pub key_falloff: f32,
+    /// straighten a photographed sheet of paper before keying
+    pub paper: Option<Paper>,
+    /// the photo shows a printed coloring page (see `template`): the fish is cut out along the
+    /// registration marks, no keying and no turning
+    pub template: bool,
+    /// what happens to specks, holes and stray regions in the mask before cropping
+    pub cleanup: MaskCleanup,
+    /// the photo shows several fish, each becomes a skin of its own
+    pub multiple_fish: bool,
+    /// with `multiple_fish`, regions smaller than this fraction of the photo are dust, not fish.
+    /// Replaces `cleanup.components`
+    pub min_fish_area: f32,
+    /// width of both output textures in pixels, 256 to 4096
+    pub output_width: u32,
+    /// width / height of the output textures, e.g. 2.0 for long fish
+    pub aspect_ratio: f32,
+    /// free space around the fish, in percent of its longer extent
+    pub padding: f32,
+    pub orientation: OrientMode,
+    /// turn the head to the other side, for fish the head detection gets wrong
+    pub flip: bool,
+    /// how the body bulge of the normal map is derived from the silhouette
+    pub height_model: HeightModel,
+    pub normals: NormalOptions,
+    /// remove the backdrop's hue from the outline of the fish
+    pub despill: bool,
+    /// neutralize the lamp's color (and maybe exposure) against a known gray
+    pub white_balance: Option<WhiteBalance>,
+    /// what color the transparent parts of the colors texture get
+    pub edge_fill: EdgeFill,
+    /// multiply the colors by alpha, for renderers that blend premultiplied
+    pub premultiplied_alpha: bool,
+    /// which measurements of the fish lead to a warning or a rejection
+    pub quality: QualityRules,
+    /// where to dump the intermediate images of a scan, see `debug::DebugDump`
+    pub debug_dir: Option<PathBuf>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            background: Background::Color(DEFAULT_BG_COLOR),
+            background_plate: None,
+            flat_field: None,
+            key_metric: KeyMetric::Rgb,
+            key_distance: None,
+            key_falloff: 0.0,
+            paper: None,
+            template: false,
+            cleanup: MaskCleanup::default(),
+            multiple_fish: false,
+            min_fish_area: 0.002,
+            output_width: 1024,
+            aspect_ratio: 1.0,
+            padding: 1.0,
+            orientation: OrientMode::Auto,
+            flip: false,
+            height_model: HeightModel::Blur,
+            normals: NormalOptions::default(),
+            despill: false,
+            white_balance: None,
+            edge_fill: EdgeFill::PullPush,
+            premultiplied_alpha: false,
+            quality: QualityRules::default(),
+            debug_dir: None,
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn with_background(bg_rgb: Rgb<u8>) -> Self {
+        ScanOptions {
+            background: Background::Color(bg_rgb),
+            ..Default::default()
+        }
+    }
+
+    /// width and height of the output textures, or an error if they are out of range
+    pub fn output_dimensions(&self) -> Result<(u32, u32), ScanError> {
+        if self.output_width < 256 || self.output_width > 4096 {
+            return Err(ScanError::InvalidOption(format!("output width {} is not in [256, 4096]", self.output_width)));
+        }
+        if !(0.25..=4.0).contains(&self.aspect_ratio) {
+            return Err(ScanError::InvalidOption(format!("aspect ratio {} is not in [0.25, 4]", self.aspect_ratio)));
+        }
+        if !(0.0..=100.0).contains(&self.padding) {
+            return Err(ScanError::InvalidOption(format!("padding {}% is not in [0, 100]", self.padding)));
+        }
+        let height = (self.output_width as f32 / self.aspect_ratio).round() as u32;
+        Ok((self.output_width, height))
+    }
+}
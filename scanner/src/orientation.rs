@@ -0,0 +1,239 @@
+//! Turning the fish so that its body axis is horizontal and its head points right,
+//! which is what `fish.gs.glsl` expects.
+
+use image::{
+    imageops,
+    ImageBuffer,
+    Pixel,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::morphology::{
+    Bitmap,
+    BoundingBox,
+};
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrientMode {
+    /// leave the fish as photographed
+    Keep,
+    /// straighten the body axis and turn the head to the right
+    Auto,
+}
+
+impl FromStr for OrientMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(OrientMode::Keep),
+            "auto" => Ok(OrientMode::Auto),
+            _ => Err(format!("unknown orientation mode: {} (keep, auto)", s)),
+        }
+    }
+}
+
+/// What was done to the photo before cropping.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Orientation {
+    /// clockwise rotation in degrees
+    pub angle: f32,
+    /// mirrored horizontally after the rotation
+    pub mirrored: bool,
+}
+
+impl Orientation {
+    pub fn is_identity(&self) -> bool {
+        self.angle.abs() < 0.5 && !self.mirrored
+    }
+}
+
+/// Angle (radians) of the fish's principal axis against the x axis, from the second order
+/// central moments of the mask. Positive angles point down to the right (image coordinates).
+pub fn principal_angle(fish: &Bitmap) -> f32 {
+    let (mut n, mut sx, mut sy) = (0f64, 0f64, 0f64);
+    for y in 0..fish.height {
+        for x in 0..fish.width {
+            if fish.get(x, y) {
+                n += 1.0;
+                sx += x as f64;
+                sy += y as f64;
+            }
+        }
+    }
+    if n == 0.0 {
+        return 0.0;
+    }
+    let (cx, cy) = (sx / n, sy / n);
+    let (mut mu20, mut mu02, mut mu11) = (0f64, 0f64, 0f64);
+    for y in 0..fish.height {
+        for x in 0..fish.width {
+            if fish.get(x, y) {
+                let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+                mu20 += dx * dx;
+                mu02 += dy * dy;
+                mu11 += dx * dy;
+            }
+        }
+    }
+    (0.5 * (2.0 * mu11).atan2(mu20 - mu02)) as f32
+}
+
+/// Guesses whether the head of a horizontal fish is on the left.
+///
+/// Most fish narrow down to a thin tail stem right before the tail fin, so the thinnest
+/// cross-section in the inner part of the body marks the tail side. If there's no clear
+/// waist, the side that carries more mass is taken as the head.
+pub fn head_on_left(fish: &Bitmap, bbox: &BoundingBox) -> bool {
+    let length = bbox.width() as usize;
+    let mut thickness = vec![0u32; length];
+    for y in bbox.top..=bbox.bottom {
+        for x in bbox.left..=bbox.right {
+            if fish.get(x, y) {
+                thickness[(x - bbox.left) as usize] += 1;
+            }
+        }
+    }
+    // smooth a little so single pen strokes don't count as a waist
+    let radius = (length / 50).max(1);
+    let smoothed: Vec<f32> = (0..length).map(|i| {
+        let from = i.saturating_sub(radius);
+        let to = (i + radius + 1).min(length);
+        thickness[from..to].iter().sum::<u32>() as f32 / (to - from) as f32
+    }).collect();
+
+    let inner = length * 15 / 100..length * 85 / 100;
+    let max = smoothed.iter().cloned().fold(0.0, f32::max);
+    let waist = inner.clone()
+        .min_by(|&a, &b| smoothed[a].partial_cmp(&smoothed[b]).unwrap());
+    if let Some(waist) = waist {
+        if smoothed[waist] < max * 0.6 {
+            // the tail is on the side of the waist, the head on the other
+            return waist > length / 2;
+        }
+    }
+
+    let mass: f32 = smoothed.iter().sum();
+    let centroid = smoothed.iter().enumerate().map(|(i, t)| i as f32 * t).sum::<f32>() / mass.max(1.0);
+    centroid < length as f32 / 2.0
+}
+
+/// The color of `img` at (`x`, `y`), bilinear between the four pixels around it.
+/// Pixels from outside `img` count as `fill`.
+pub fn sample_bilinear<P>(img: &ImageBuffer<P, Vec<u16>>, x: f32, y: f32, fill: P) -> P
+where
+    P: Pixel<Subpixel = u16> + 'static,
+{
+    let (width, height) = img.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let pixel = |x: i64, y: i64| -> P {
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+            fill
+        } else {
+            *img.get_pixel(x as u32, y as u32)
+        }
+    };
+    let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let corners = [
+        (pixel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (pixel(x0 + 1, y0), fx * (1.0 - fy)),
+        (pixel(x0, y0 + 1), (1.0 - fx) * fy),
+        (pixel(x0 + 1, y0 + 1), fx * fy),
+    ];
+    let mut out = [0u16; 4];
+    for (c, value) in out.iter_mut().enumerate().take(channels) {
+        let v: f32 = corners.iter().map(|(p, w)| p.channels()[c] as f32 * w).sum();
+        *value = v.round() as u16;
+    }
+    *P::from_slice(&out[..channels])
+}
+
+/// Rotates `img` clockwise by `angle` radians around (`cx`, `cy`) into a `width` x `height`
+/// canvas centered on that point, bilinear. Pixels from outside `img` become `fill`.
+pub fn rotate<P>(img: &ImageBuffer<P, Vec<u16>>, cx: f32, cy: f32, angle: f32, width: u32, height: u32, fill: P) -> ImageBuffer<P, Vec<u16>>
+where
+    P: Pixel<Subpixel = u16> + 'static,
+{
+    let (sin, cos) = angle.sin_cos();
+    ImageBuffer::from_fn(width, height, |u, v| {
+        // inverse mapping: where in the source does this output pixel come from
+        let du = u as f32 - width as f32 / 2.0;
+        let dv = v as f32 - height as f32 / 2.0;
+        let x = cx + du * cos + dv * sin;
+        let y = cy - du * sin + dv * cos;
+        sample_bilinear(img, x, y, fill)
+    })
+}
+
+/// Straightens the fish: returns the rotated (and maybe mirrored) image, cut to a square
+/// around the fish (`bbox`) that is big enough for any rotation.
+pub fn orient<P>(img: &ImageBuffer<P, Vec<u16>>, bbox: &BoundingBox, orientation: &Orientation, fill: P) -> ImageBuffer<P, Vec<u16>>
+where
+    P: Pixel<Subpixel = u16> + 'static,
+{
+    let cx = (bbox.left + bbox.right) as f32 / 2.0;
+    let cy = (bbox.top + bbox.bottom) as f32 / 2.0;
+    let diagonal = ((bbox.width() as f32).powi(2) + (bbox.height() as f32).powi(2)).sqrt();
+    let side = (diagonal * 1.2).ceil() as u32 + 2;
+    let angle = orientation.angle.to_radians();
+    let mut img = rotate(img, cx, cy, angle, side, side, fill);
+    if orientation.mirrored {
+        imageops::flip_horizontal_in_place(&mut img);
+    }
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fish shape along the x axis, head (thick end) on the right, turned clockwise by
+    /// `angle` degrees around the middle of a 200x200 bitmap and mirrored if asked
+    fn fish(angle: f32, mirrored: bool) -> Bitmap {
+        let mut bitmap = Bitmap::new(200, 200);
+        let (sin, cos) = (-angle.to_radians()).sin_cos();
+        for y in 0..200 {
+            for x in 0..200 {
+                // back into the fish's own frame
+                let (dx, dy) = (x as f32 - 100.0, y as f32 - 100.0);
+                let (u, v) = (dx * cos - dy * sin, dx * sin + dy * cos);
+                let u = if mirrored { -u } else { u };
+                // a tail fin from -80 to -60, a thin stem up to -40, then a body growing to the head
+                let half_thickness = if !(-80.0..=80.0).contains(&u) {
+                    -1.0
+                } else if u < -60.0 {
+                    12.0
+                } else if u < -40.0 {
+                    3.0
+                } else {
+                    8.0 + (u + 40.0) * 0.2
+                };
+                bitmap.set(x, y, v.abs() <= half_thickness);
+            }
+        }
+        bitmap
+    }
+
+    #[test]
+    fn principal_angle_of_a_turned_fish() {
+        for angle in [0.0f32, 20.0, -35.0, 60.0] {
+            let found = principal_angle(&fish(angle, false)).to_degrees();
+            assert!((found - angle).abs() < 1.0, "turned by {} found {}", angle, found);
+        }
+    }
+
+    #[test]
+    fn head_of_a_mirrored_fish() {
+        let right = fish(0.0, false);
+        assert!(!head_on_left(&right, &right.bounding_box().unwrap()));
+        let left = fish(0.0, true);
+        assert!(head_on_left(&left, &left.bounding_box().unwrap()));
+    }
+}
@@ -0,0 +1,127 @@
+//! Naming of the files `sea` loads: `./fish/0001-colors.png`, `./fish/0001-normals.png`, ...
+
+use image::ImageFormat;
+
+use crate::{
+    error::ScanError,
+    manifest::FishManifest,
+    FishSkin,
+};
+
+use std::{
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+pub fn colors_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
+    dir.as_ref().join(format!("{:04}-colors.png", number))
+}
+
+pub fn normals_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
+    dir.as_ref().join(format!("{:04}-normals.png", number))
+}
+
+pub fn manifest_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
+    dir.as_ref().join(format!("{:04}-fish.json", number))
+}
+
+pub fn quality_path<P: AsRef<Path>>(dir: P, number: u32) -> PathBuf {
+    dir.as_ref().join(format!("{:04}-quality.json", number))
+}
+
+/// Saves `skin`, its manifest and quality report as fish `number` in `dir` so that readers
+/// never see half written files: everything is written to hidden temporary files first and
+/// then renamed into place, the colors last.
+pub fn publish<P: AsRef<Path>>(skin: &FishSkin, manifest: &FishManifest, dir: P, number: u32) -> Result<(), ScanError> {
+    let dir = dir.as_ref();
+    let file_name = |path: PathBuf| path.file_name().unwrap().to_string_lossy().into_owned();
+    let mut manifest = manifest.clone();
+    manifest.colors = file_name(colors_path(dir, number));
+    manifest.normals = file_name(normals_path(dir, number));
+
+    let colors_tmp = dir.join(format!(".{:04}-colors.png.tmp", number));
+    let normals_tmp = dir.join(format!(".{:04}-normals.png.tmp", number));
+    let manifest_tmp = dir.join(format!(".{:04}-fish.json.tmp", number));
+    let quality_tmp = dir.join(format!(".{:04}-quality.json.tmp", number));
+    skin.colors.save_with_format(&colors_tmp, ImageFormat::Png)?;
+    skin.normals.save_with_format(&normals_tmp, ImageFormat::Png)?;
+    manifest.save(&manifest_tmp)?;
+    skin.quality.save(&quality_tmp)?;
+    fs::rename(&quality_tmp, quality_path(dir, number))?;
+    fs::rename(&manifest_tmp, manifest_path(dir, number))?;
+    fs::rename(&normals_tmp, normals_path(dir, number))?;
+    fs::rename(&colors_tmp, colors_path(dir, number))?;
+    Ok(())
+}
+
+/// the number of a `NNNN-*` file name, if it has one
+pub fn fish_number(file_name: &str) -> Option<u32> {
+    let dash = file_name.find('-')?;
+    if dash == 0 || !file_name[..dash].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    file_name[..dash].parse().ok()
+}
+
+/// The first free fish number in `dir`, one above the highest one in use.
+pub fn next_fish_number<P: AsRef<Path>>(dir: P) -> io::Result<u32> {
+    let mut highest = 0;
+    for entry in fs::read_dir(dir)? {
+        if let Some(number) = entry?.file_name().to_str().and_then(fish_number) {
+            highest = highest.max(number);
+        }
+    }
+    Ok(highest + 1)
+}
+
+/// true for file extensions the scanner can read: what `image` decodes, and camera raw files
+pub fn is_image_file<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension().and_then(|e| e.to_str()) {
+        Some(ext) => {
+            let ext = ext.to_ascii_lowercase();
+            ["png", "jpg", "jpeg", "tif", "tiff", "bmp", "gif", "webp", "tga", "pnm", "ppm", "pgm", "cr2", "dng"].contains(&ext.as_str())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory of its own for every test, and for every test run
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scanner-output-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn numbers_of_file_names() {
+        assert_eq!(fish_number("0001-colors.png"), Some(1));
+        assert_eq!(fish_number("0123-fish.json"), Some(123));
+        assert_eq!(fish_number("12345-normals.png"), Some(12345));
+        for name in ["colors.png", "-0001-colors.png", "12a-colors.png", ".0002-colors.png.tmp", "0001", ""] {
+            assert_eq!(fish_number(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn next_number_follows_the_highest() {
+        let dir = scratch_dir("next");
+        assert_eq!(next_fish_number(&dir).unwrap(), 1);
+        for name in ["0001-colors.png", "0007-fish.json", "notes.txt", ".0009-colors.png.tmp", "2024-photo-"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        // a gap stays a gap, and anything numbered counts, not just skins
+        assert_eq!(next_fish_number(&dir).unwrap(), 2025);
+        fs::remove_file(dir.join("2024-photo-")).unwrap();
+        assert_eq!(next_fish_number(&dir).unwrap(), 8);
+        assert!(next_fish_number(dir.join("missing")).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Dominant colors of a fish skin.
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::ColorsImage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DominantColor {
+    pub rgb: [u8; 3],
+    /// fraction of the fish's opaque pixels closest to this color
+    pub share: f32,
+}
+
+/// pixels with less alpha than this don't belong to the fish
+const MIN_ALPHA: u8 = 200;
+/// k-means works on at most this many pixels, that's plenty for a handful of colors
+const MAX_SAMPLES: usize = 20_000;
+const ITERATIONS: usize = 12;
+
+/// Clusters the opaque pixels of `colors` into (at most) `count` colors with k-means,
+/// most common color first. Deterministic: the same skin always gives the same palette.
+pub fn dominant_colors(colors: &ColorsImage, count: usize) -> Vec<DominantColor> {
+    let opaque: Vec<[f32; 3]> = colors.pixels()
+        .filter(|p| p[3] >= MIN_ALPHA)
+        .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+        .collect();
+    if opaque.is_empty() || count == 0 {
+        return Vec::new();
+    }
+    let step = (opaque.len() / MAX_SAMPLES).max(1);
+    let samples: Vec<[f32; 3]> = opaque.into_iter().step_by(step).collect();
+
+    let distance = |a: &[f32; 3], b: &[f32; 3]| {
+        (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+    };
+    let nearest = |centers: &[[f32; 3]], p: &[f32; 3]| {
+        let mut best = 0;
+        for (i, c) in centers.iter().enumerate() {
+            if distance(c, p) < distance(&centers[best], p) {
+                best = i;
+            }
+        }
+        best
+    };
+
+    // farthest point initialisation, starting at the mean
+    let mut mean = [0.0; 3];
+    for p in &samples {
+        for c in 0..3 {
+            mean[c] += p[c] / samples.len() as f32;
+        }
+    }
+    let mut centers = vec![mean];
+    while centers.len() < count {
+        let farthest = samples.iter()
+            .map(|p| (p, distance(&centers[nearest(&centers, p)], p)))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .unwrap();
+        if farthest.1 < 1.0 {
+            // fewer distinct colors than asked for
+            break;
+        }
+        centers.push(*farthest.0);
+    }
+
+    let mut members = vec![0usize; centers.len()];
+    for _ in 0..ITERATIONS {
+        let mut sums = vec![[0.0f32; 3]; centers.len()];
+        members = vec![0; centers.len()];
+        for p in &samples {
+            let i = nearest(&centers, p);
+            members[i] += 1;
+            for c in 0..3 {
+                sums[i][c] += p[c];
+            }
+        }
+        for (i, center) in centers.iter_mut().enumerate() {
+            if members[i] > 0 {
+                for c in 0..3 {
+                    center[c] = sums[i][c] / members[i] as f32;
+                }
+            }
+        }
+    }
+
+    let mut palette: Vec<DominantColor> = centers.iter().zip(members.iter())
+        .filter(|(_, &n)| n > 0)
+        .map(|(center, &n)| DominantColor {
+            rgb: [center[0].round() as u8, center[1].round() as u8, center[2].round() as u8],
+            share: n as f32 / samples.len() as f32,
+        })
+        .collect();
+    palette.sort_by(|a, b| b.share.partial_cmp(&a.share).unwrap());
+    palette
+}
@@ -0,0 +1,290 @@
+//! Undoing perspective: mapping a photographed quadrilateral back to a rectangle.
+
+use image::{
+    imageops,
+    imageops::FilterType,
+    DynamicImage,
+    ImageBuffer,
+    Luma,
+    Pixel,
+};
+
+use crate::{
+    debug::DebugDump,
+    error::ScanError,
+    input::Photo,
+    linalg::solve,
+    morphology::{
+        Bitmap,
+        ComponentFilter,
+    },
+    orientation::sample_bilinear,
+};
+
+/// the photo is searched for the paper at about this size
+const DETECTION_SIZE: u32 = 800;
+/// detected corners are pulled in by this fraction of the paper's size,
+/// so no sliver of the table ends up along the edges
+const PAPER_INSET: f32 = 0.01;
+
+/// Where the sheet of paper with the drawing is in the photo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Paper {
+    /// the biggest bright quadrilateral, paper on a darker table
+    Detect,
+    /// corners in photo pixels, clockwise from the top left of the drawing
+    Corners([(f32, f32); 4]),
+}
+
+/// A projective transform of the plane, row major with the last entry fixed to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homography([f64; 9]);
+
+impl Homography {
+    /// The transform that maps each of `from` to the corresponding point of `to`.
+    /// `None` if three of the points are on a line.
+    pub fn from_points(from: &[(f32, f32); 4], to: &[(f32, f32); 4]) -> Option<Homography> {
+        // u = (h0 x + h1 y + h2) / (h6 x + h7 y + 1), v likewise with h3..h5,
+        // multiplied out that's two linear equations per point
+        let (mut a, mut b) = ([[0f64; 8]; 8], [0f64; 8]);
+        for (i, (&(x, y), &(u, v))) in from.iter().zip(to.iter()).enumerate() {
+            let (x, y, u, v) = (x as f64, y as f64, u as f64, v as f64);
+            a[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u];
+            a[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v];
+            b[2 * i] = u;
+            b[2 * i + 1] = v;
+        }
+        let mut h = [1f64; 9];
+        h[..8].copy_from_slice(&solve(a, b)?);
+        Some(Homography(h))
+    }
+
+    pub fn map(&self, x: f32, y: f32) -> (f32, f32) {
+        let h = &self.0;
+        let (x, y) = (x as f64, y as f64);
+        let w = h[6] * x + h[7] * y + h[8];
+        (((h[0] * x + h[1] * y + h[2]) / w) as f32, ((h[3] * x + h[4] * y + h[5]) / w) as f32)
+    }
+}
+
+/// Resamples `img` into a `width` x `height` image, bilinear. `to_source` maps every output pixel
+/// to where it comes from in `img`; pixels from outside `img` become `fill`.
+pub fn warp<P>(img: &ImageBuffer<P, Vec<u16>>, to_source: &Homography, width: u32, height: u32, fill: P) -> ImageBuffer<P, Vec<u16>>
+where
+    P: Pixel<Subpixel = u16> + 'static,
+{
+    ImageBuffer::from_fn(width, height, |u, v| {
+        let (x, y) = to_source.map(u as f32, v as f32);
+        sample_bilinear(img, x, y, fill)
+    })
+}
+
+/// Finds the corners of a sheet of paper that is brighter than the table it lies on,
+/// clockwise from the top left.
+pub fn find_paper(img: &Photo, debug: &mut DebugDump) -> Result<[(f32, f32); 4], ScanError> {
+    let (width, height) = img.dimensions();
+    let scale = (DETECTION_SIZE as f32 / width.max(height) as f32).min(1.0);
+    let grey = imageops::colorops::grayscale(&img.rgb);
+    let small = imageops::resize(
+        &grey,
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+        FilterType::Triangle,
+    );
+    let threshold = otsu_threshold(&small);
+    let bright = Bitmap {
+        width: small.width(),
+        height: small.height(),
+        data: small.pixels().map(|p| p[0] > threshold).collect(),
+    };
+    // the drawing on the paper is darker, filling holes brings it back
+    let paper = bright.filter_components(ComponentFilter::Largest).fill_holes();
+    debug.add("paper mask", || DynamicImage::ImageLuma16(paper.to_mask()));
+    if paper.area() < paper.width * paper.height / 20 {
+        return Err(ScanError::PaperNotFound);
+    }
+
+    // the outline of the paper: left and right end of every row, then their convex hull
+    let mut points = Vec::new();
+    for y in 0..paper.height {
+        let mut row = (0..paper.width).filter(|&x| paper.get(x, y));
+        let (first, last) = (row.next(), row.next_back());
+        if let Some(first) = first {
+            points.push((first as f32, y as f32));
+            points.push((last.unwrap_or(first) as f32 + 1.0, y as f32));
+        }
+    }
+    let hull = convex_hull(points);
+    // the two hull points furthest apart are opposite corners, the other two are the points
+    // furthest from that diagonal on either side
+    let mut diagonal = (0, 0, 0.0);
+    for (i, a) in hull.iter().enumerate() {
+        for (j, b) in hull.iter().enumerate().skip(i + 1) {
+            let d = (a.0 - b.0).hypot(a.1 - b.1);
+            if d > diagonal.2 {
+                diagonal = (i, j, d);
+            }
+        }
+    }
+    let (a, c) = (hull[diagonal.0], hull[diagonal.1]);
+    let side = |p: &(f32, f32)| (c.0 - a.0) * (p.1 - a.1) - (c.1 - a.1) * (p.0 - a.0);
+    let furthest = |sign: f32| hull.iter().copied().max_by(|p, q| (side(p) * sign).partial_cmp(&(side(q) * sign)).unwrap());
+    let (b, d) = match (furthest(1.0), furthest(-1.0)) {
+        (Some(b), Some(d)) if side(&b) > 0.0 && side(&d) < 0.0 => (b, d),
+        _ => return Err(ScanError::PaperNotFound),
+    };
+
+    let corners = clockwise([a, b, c, d]);
+    let cx = corners.iter().map(|p| p.0).sum::<f32>() / 4.0;
+    let cy = corners.iter().map(|p| p.1).sum::<f32>() / 4.0;
+    Ok(corners.map(|(x, y)| {
+        let (x, y) = (x + (cx - x) * PAPER_INSET * 2.0, y + (cy - y) * PAPER_INSET * 2.0);
+        (x / scale, y / scale)
+    }))
+}
+
+/// Width / height of the rectangle that appears as `corners` (clockwise from the top left) in a photo
+/// centered on the optical axis, from the camera's focal length, which the quadrilateral pins down
+/// (Zhang & He, "Whiteboard scanning and image enhancement", 2007).
+/// `None` if it doesn't, e.g. when the paper was photographed (almost) straight on.
+fn rectangle_aspect(corners: &[(f32, f32); 4], center: (f32, f32)) -> Option<f32> {
+    let m = corners.map(|(x, y)| [(x - center.0) as f64, (y - center.1) as f64, 1.0]);
+    let (m1, m2, m3, m4) = (m[0], m[1], m[3], m[2]);
+    let cross = |a: [f64; 3], b: [f64; 3]| [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+    let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let k2 = dot(cross(m1, m4), m3) / dot(cross(m2, m4), m3);
+    let k3 = dot(cross(m1, m4), m2) / dot(cross(m3, m4), m2);
+    let n2 = [k2 * m2[0] - m1[0], k2 * m2[1] - m1[1], k2 * m2[2] - m1[2]];
+    let n3 = [k3 * m3[0] - m1[0], k3 * m3[1] - m1[1], k3 * m3[2] - m1[2]];
+    if (n2[2] * n3[2]).abs() < 1e-9 {
+        return None;
+    }
+    let focal_squared = -(n2[0] * n3[0] + n2[1] * n3[1]) / (n2[2] * n3[2]);
+    // close to straight on the estimate is mostly noise, real lenses are somewhere in this range
+    let diagonal = 2.0 * (center.0 as f64).hypot(center.1 as f64);
+    if !(0.3 * diagonal..=5.0 * diagonal).contains(&focal_squared.sqrt()) {
+        return None;
+    }
+    let width = (n2[0] * n2[0] + n2[1] * n2[1]) / focal_squared + n2[2] * n2[2];
+    let height = (n3[0] * n3[0] + n3[1] * n3[1]) / focal_squared + n3[2] * n3[2];
+    let aspect = (width / height).sqrt() as f32;
+    if aspect.is_finite() && aspect > 0.0 { Some(aspect) } else { None }
+}
+
+/// The paper, straightened into a rectangle about as wide as it is in the photo.
+/// `corners` are clockwise from the top left.
+pub fn rectify_paper(photo: &Photo, corners: &[(f32, f32); 4]) -> Result<Photo, ScanError> {
+    let length = |a: (f32, f32), b: (f32, f32)| (a.0 - b.0).hypot(a.1 - b.1);
+    let width = (length(corners[0], corners[1]) + length(corners[3], corners[2])) / 2.0;
+    let height = (length(corners[0], corners[3]) + length(corners[1], corners[2])) / 2.0;
+    // a tilted camera foreshortens the paper, so the edge lengths alone get the proportions wrong
+    let (photo_width, photo_height) = photo.dimensions();
+    let aspect = rectangle_aspect(corners, (photo_width as f32 / 2.0, photo_height as f32 / 2.0))
+        .filter(|aspect| (0.1..=10.0).contains(aspect))
+        .unwrap_or(width / height.max(1.0));
+    let height = (width / aspect).round() as u32;
+    let width = width.round() as u32;
+    if width < 16 || height < 16 {
+        return Err(ScanError::InvalidOption(format!("the paper is only {}x{} pixels", width, height)));
+    }
+    let (w, h) = (width as f32 - 1.0, height as f32 - 1.0);
+    let to_photo = Homography::from_points(&[(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)], corners).ok_or_else(|| {
+        ScanError::InvalidOption("three of the paper's corners are on a line".to_string())
+    })?;
+    // the paper's own color fills whatever lies outside the photo, keying treats it as background
+    let (x, y) = to_photo.map(w * 0.03, h * 0.03);
+    let fill = *photo.rgb.get_pixel(
+        (x.max(0.0) as u32).min(photo.rgb.width() - 1),
+        (y.max(0.0) as u32).min(photo.rgb.height() - 1),
+    );
+    Ok(Photo {
+        rgb: warp(&photo.rgb, &to_photo, width, height, fill),
+        alpha: photo.alpha.as_ref().map(|alpha| warp(alpha, &to_photo, width, height, Luma([0]))),
+    })
+}
+
+/// clockwise on screen (y pointing down), starting with the point nearest to the top left
+pub fn clockwise(mut points: [(f32, f32); 4]) -> [(f32, f32); 4] {
+    let cx = points.iter().map(|p| p.0).sum::<f32>() / 4.0;
+    let cy = points.iter().map(|p| p.1).sum::<f32>() / 4.0;
+    let angle = |p: &(f32, f32)| (p.1 - cy).atan2(p.0 - cx);
+    points.sort_by(|a, b| angle(a).partial_cmp(&angle(b)).unwrap());
+    let first = (0..4).min_by(|&i, &j| (points[i].0 + points[i].1).partial_cmp(&(points[j].0 + points[j].1)).unwrap()).unwrap_or(0);
+    points.rotate_left(first);
+    points
+}
+
+/// Andrew's monotone chain, counter-clockwise on screen
+fn convex_hull(mut points: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+    points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+    let cross = |o: (f32, f32), a: (f32, f32), b: (f32, f32)| (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+    let mut hull: Vec<(f32, f32)> = Vec::with_capacity(points.len() * 2);
+    for pass in 0..2 {
+        let start = hull.len();
+        let ordered: Box<dyn Iterator<Item = &(f32, f32)>> = if pass == 0 { Box::new(points.iter()) } else { Box::new(points.iter().rev()) };
+        for &p in ordered {
+            while hull.len() >= start + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0.0 {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        hull.pop();
+    }
+    hull
+}
+
+/// the threshold that best splits the histogram of `img` into a dark and a bright class
+fn otsu_threshold(img: &ImageBuffer<Luma<u16>, Vec<u16>>) -> u16 {
+    let mut histogram = [0u64; 256];
+    for p in img.pixels() {
+        histogram[(p[0] >> 8) as usize] += 1;
+    }
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram.iter().enumerate().map(|(i, &n)| i as f64 * n as f64).sum();
+    let (mut dark_count, mut dark_sum) = (0u64, 0f64);
+    let mut best = (0, 0f64);
+    for (i, &n) in histogram.iter().enumerate() {
+        dark_count += n;
+        dark_sum += i as f64 * n as f64;
+        let bright_count = total - dark_count;
+        if dark_count == 0 || bright_count == 0 {
+            continue;
+        }
+        let dark_mean = dark_sum / dark_count as f64;
+        let bright_mean = (sum - dark_sum) / bright_count as f64;
+        let between = dark_count as f64 * bright_count as f64 * (dark_mean - bright_mean).powi(2);
+        if between > best.1 {
+            best = (i, between);
+        }
+    }
+    ((best.0 as u16) << 8) | 0xff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn homography_maps_the_points_it_was_made_from() {
+        let from = [(112.0, 80.0), (950.0, 130.0), (1010.0, 700.0), (60.0, 640.0)];
+        let to = [(0.0, 0.0), (1024.0, 0.0), (1024.0, 724.0), (0.0, 724.0)];
+        let h = Homography::from_points(&from, &to).unwrap();
+        for (&(x, y), &(u, v)) in from.iter().zip(to.iter()) {
+            let (mu, mv) = h.map(x, y);
+            assert!((mu - u).abs() < 1e-3 && (mv - v).abs() < 1e-3, "({}, {}) -> ({}, {})", x, y, mu, mv);
+        }
+        let identity = Homography::from_points(&to, &to).unwrap();
+        assert_eq!(identity.map(300.0, 200.0), (300.0, 200.0));
+    }
+
+    #[test]
+    fn no_homography_from_points_on_a_line() {
+        let line = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)];
+        let square = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        assert_eq!(Homography::from_points(&line, &square), None);
+    }
+}
@@ -0,0 +1,384 @@
+//! How much a scan can be trusted: a few measurements of every fish and rules that turn them
+//! into warnings or a rejection. The report is saved next to the textures as `NNNN-quality.json`.
+
+use image::Rgb;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::{
+    error::ScanError,
+    input::{
+        to_u8,
+        Rgb16Image,
+    },
+    keying::Keyer,
+    morphology::{
+        Bitmap,
+        BoundingBox,
+    },
+};
+
+use std::{
+    fmt,
+    fs,
+    io,
+    path::Path,
+    str::FromStr,
+};
+
+/// What happens when a check fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ignore,
+    /// the skin is made, the finding ends up in the report
+    Warn,
+    /// no skin, the scan fails with `ScanError::Rejected`
+    Reject,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Severity::Ignore),
+            "warn" => Ok(Severity::Warn),
+            "reject" => Ok(Severity::Reject),
+            _ => Err(format!("unknown severity: {} (ignore, warn, reject)", s)),
+        }
+    }
+}
+
+/// The measurements rules can be set for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    Border,
+    MinCoverage,
+    MaxCoverage,
+    Components,
+    KeyConfidence,
+    Sharpness,
+}
+
+impl FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "border" => Ok(Check::Border),
+            "min-coverage" => Ok(Check::MinCoverage),
+            "max-coverage" => Ok(Check::MaxCoverage),
+            "components" => Ok(Check::Components),
+            "key-confidence" => Ok(Check::KeyConfidence),
+            "sharpness" => Ok(Check::Sharpness),
+            _ => Err(format!(
+                "unknown check: {} (border, min-coverage, max-coverage, components, key-confidence, sharpness)",
+                s,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Check::Border => "border",
+            Check::MinCoverage => "min-coverage",
+            Check::MaxCoverage => "max-coverage",
+            Check::Components => "components",
+            Check::KeyConfidence => "key-confidence",
+            Check::Sharpness => "sharpness",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub severity: Severity,
+    /// the measurement's limit, a maximum or minimum depending on the check
+    pub limit: f32,
+}
+
+/// Limits for the measurements of `QualityReport`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityRules {
+    /// most fish pixels on the image edge, more mean the fish may be cut off. Only warns by
+    /// default, tightly cropped drawings with an alpha channel reach the edge on purpose
+    pub border: Rule,
+    /// least fraction of the photo the fish has to cover, smaller fish make blurry textures
+    pub min_coverage: Rule,
+    /// most fraction of the photo that may be foreground, more usually means a wrong background color
+    pub max_coverage: Rule,
+    /// most regions in the mask before cleanup
+    pub components: Rule,
+    pub key_confidence: Rule,
+    pub sharpness: Rule,
+}
+
+impl Default for QualityRules {
+    fn default() -> Self {
+        QualityRules {
+            border: Rule { severity: Severity::Warn, limit: 0.0 },
+            min_coverage: Rule { severity: Severity::Warn, limit: 0.001 },
+            max_coverage: Rule { severity: Severity::Reject, limit: 0.9 },
+            components: Rule { severity: Severity::Warn, limit: 200.0 },
+            key_confidence: Rule { severity: Severity::Warn, limit: 0.5 },
+            sharpness: Rule { severity: Severity::Warn, limit: 0.25 },
+        }
+    }
+}
+
+impl QualityRules {
+    pub fn rule_mut(&mut self, check: Check) -> &mut Rule {
+        match check {
+            Check::Border => &mut self.border,
+            Check::MinCoverage => &mut self.min_coverage,
+            Check::MaxCoverage => &mut self.max_coverage,
+            Check::Components => &mut self.components,
+            Check::KeyConfidence => &mut self.key_confidence,
+            Check::Sharpness => &mut self.sharpness,
+        }
+    }
+
+    /// Fills in the findings of `report`.
+    pub fn judge(&self, report: &mut QualityReport) {
+        let mut findings = Vec::new();
+        let mut check = |check: Check, rule: &Rule, failed: bool, message: String| {
+            if failed && rule.severity != Severity::Ignore {
+                findings.push(Finding { check, severity: rule.severity, message });
+            }
+        };
+        check(
+            Check::Border,
+            &self.border,
+            report.border_contact as f32 > self.border.limit,
+            "the fish touches the image border".to_string(),
+        );
+        check(
+            Check::MinCoverage,
+            &self.min_coverage,
+            report.coverage < self.min_coverage.limit,
+            format!("the fish covers only {:.2}% of the photo", report.coverage * 100.0),
+        );
+        check(
+            Check::MaxCoverage,
+            &self.max_coverage,
+            report.coverage > self.max_coverage.limit,
+            format!("{:.0}% of the photo is foreground, is the background color right?", report.coverage * 100.0),
+        );
+        check(
+            Check::Components,
+            &self.components,
+            report.components as f32 > self.components.limit,
+            format!("{} separate regions in the mask, the key is noisy", report.components),
+        );
+        if let Some(confidence) = report.key_confidence {
+            check(
+                Check::KeyConfidence,
+                &self.key_confidence,
+                confidence < self.key_confidence.limit,
+                format!("the fish hardly stands out from the backdrop (key confidence {:.2})", confidence),
+            );
+        }
+        check(
+            Check::Sharpness,
+            &self.sharpness,
+            report.sharpness < self.sharpness.limit,
+            format!("the photo looks blurry (sharpness {:.1})", report.sharpness),
+        );
+        report.findings = findings;
+    }
+}
+
+/// A failed check.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Finding {
+    pub check: Check,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Measurements of one fish.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityReport {
+    /// fraction of the photo covered by the fish
+    pub coverage: f32,
+    /// fish pixels on the image edge
+    pub border_contact: u32,
+    /// separate regions of the mask before cleanup within the fish's bounds: the fish, and
+    /// specks of noise or dust
+    pub components: usize,
+    /// 0 to 1, how few pixels around the fish have colors close to the key distance, where
+    /// a slightly different backdrop or lamp would have flipped them. `None` without a color key
+    pub key_confidence: Option<f32>,
+    /// how steep the edges of the fish and its drawing are, see `sharpness`. Photos in focus
+    /// give 0.5 and more, below 0.25 edges are smeared over three pixels or more
+    pub sharpness: f32,
+    /// the checks that failed, see `QualityRules`
+    pub findings: Vec<Finding>,
+}
+
+impl QualityReport {
+    pub fn rejected(&self) -> bool {
+        self.findings.iter().any(|finding| finding.severity == Severity::Reject)
+    }
+
+    /// whether `check` is among the reasons for rejecting the fish
+    pub fn rejected_by(&self, check: Check) -> bool {
+        self.findings.iter().any(|finding| finding.check == check && finding.severity == Severity::Reject)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(|finding| finding.severity == Severity::Warn)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ScanError> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::from)?;
+        fs::write(path, json + "\n")?;
+        Ok(())
+    }
+}
+
+/// number of pixels of `fish` in the outermost rows and columns
+pub fn border_contact(fish: &Bitmap) -> u32 {
+    let (width, height) = (fish.width, fish.height);
+    let rows = (0..width).map(|x| fish.get(x, 0) as u32 + fish.get(x, height - 1) as u32).sum::<u32>();
+    let columns = (1..height - 1).map(|y| fish.get(0, y) as u32 + fish.get(width - 1, y) as u32).sum::<u32>();
+    rows + columns
+}
+
+/// 1 minus the ambiguous pixels in and around the fish's bounding box relative to the fish's
+/// area. Ambiguous are colors between half and one and a half times the key distance away from
+/// the backdrop: a clean photo only has them along the outline.
+pub fn key_confidence(rgb: &Rgb16Image, fish: &Bitmap, bbox: &BoundingBox, keyer: &Keyer, distance: f32) -> f32 {
+    let (width, height) = rgb.dimensions();
+    let margin = bbox.width().max(bbox.height()) / 10;
+    let mut ambiguous = 0u32;
+    for y in bbox.top.saturating_sub(margin)..(bbox.bottom + margin + 1).min(height) {
+        for x in bbox.left.saturating_sub(margin)..(bbox.right + margin + 1).min(width) {
+            let p = rgb.get_pixel(x, y);
+            let d = keyer.distance(&Rgb([to_u8(p[0]), to_u8(p[1]), to_u8(p[2])]));
+            if (d - distance).abs() < distance * 0.5 {
+                ambiguous += 1;
+            }
+        }
+    }
+    (1.0 - ambiguous as f32 / fish.area().max(1) as f32).max(0.0)
+}
+
+/// brightness steps (0 to 255) per pixel below this aren't edges for `sharpness`
+const MIN_EDGE_GRADIENT: f64 = 2.0;
+
+/// How steep the edges in and around the fish are, at the photo's resolution: the summed
+/// laplacian over the summed gradient of the brightness where there is an edge. An edge blurred
+/// with a radius of `r` pixels gives roughly `0.7 / r`, whatever its contrast or how much is drawn.
+/// Sensor noise makes photos look a bit sharper than they are.
+pub fn sharpness(rgb: &Rgb16Image, fish: &Bitmap, bbox: &BoundingBox) -> f32 {
+    let (width, height) = rgb.dimensions();
+    let near = fish.dilate(2);
+    let brightness = |x: u32, y: u32| {
+        let p = rgb.get_pixel(x, y);
+        (0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64) / 257.0
+    };
+    let (mut laplacians, mut gradients) = (0.0, 0.0);
+    for y in bbox.top.saturating_sub(2).max(1)..(bbox.bottom + 3).min(height - 1) {
+        for x in bbox.left.saturating_sub(2).max(1)..(bbox.right + 3).min(width - 1) {
+            if !near.get(x, y) {
+                continue;
+            }
+            let (left, right, up, down) = (brightness(x - 1, y), brightness(x + 1, y), brightness(x, y - 1), brightness(x, y + 1));
+            let gradient = ((right - left).powi(2) + (down - up).powi(2)).sqrt() / 2.0;
+            // flat areas only add noise
+            if gradient < MIN_EDGE_GRADIENT {
+                continue;
+            }
+            laplacians += (left + right + up + down - 4.0 * brightness(x, y)).abs();
+            gradients += gradient;
+        }
+    }
+    if gradients == 0.0 {
+        return 0.0;
+    }
+    (laplacians / gradients) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::imageops;
+
+    /// a report that passes every default rule
+    fn report(fish: &Bitmap) -> QualityReport {
+        QualityReport {
+            coverage: 0.25,
+            border_contact: border_contact(fish),
+            components: 1,
+            key_confidence: Some(0.9),
+            sharpness: 0.6,
+            findings: Vec::new(),
+        }
+    }
+
+    /// a square of `size` pixels at `left`, `top` in a 64x64 bitmap
+    fn square(left: u32, top: u32, size: u32) -> Bitmap {
+        let mut fish = Bitmap::new(64, 64);
+        for y in top..top + size {
+            for x in left..left + size {
+                fish.set(x, y, true);
+            }
+        }
+        fish
+    }
+
+    fn checks(report: &QualityReport) -> Vec<(Check, Severity)> {
+        report.findings.iter().map(|finding| (finding.check, finding.severity)).collect()
+    }
+
+    #[test]
+    fn single_component_passes() {
+        let mut report = report(&square(16, 16, 32));
+        QualityRules::default().judge(&mut report);
+        assert_eq!(checks(&report), []);
+        assert!(!report.rejected());
+    }
+
+    #[test]
+    fn border_contact_warns_or_rejects() {
+        let fish = square(0, 16, 32);
+        assert_eq!(border_contact(&fish), 32);
+        let mut report = report(&fish);
+        QualityRules::default().judge(&mut report);
+        assert_eq!(checks(&report), [(Check::Border, Severity::Warn)]);
+        assert!(!report.rejected());
+
+        let mut rules = QualityRules::default();
+        rules.rule_mut(Check::Border).severity = Severity::Reject;
+        rules.judge(&mut report);
+        assert_eq!(checks(&report), [(Check::Border, Severity::Reject)]);
+        assert!(report.rejected());
+        assert!(report.rejected_by(Check::Border));
+        assert!(!report.rejected_by(Check::Sharpness));
+    }
+
+    #[test]
+    fn blurry_photo_is_flagged() {
+        let fish = square(8, 8, 48);
+        let bbox = fish.bounding_box().unwrap();
+        let stripes = Rgb16Image::from_fn(64, 64, |x, _| Rgb([if x / 16 % 2 == 0 { 5000 } else { 60000 }; 3]));
+        let sharp = sharpness(&stripes, &fish, &bbox);
+        let blurry = sharpness(&imageops::blur(&stripes, 4.0), &fish, &bbox);
+        assert!(sharp > 0.5, "sharp stripes measured {}", sharp);
+        assert!(blurry < 0.25, "blurred stripes measured {}", blurry);
+
+        let mut report = QualityReport { sharpness: blurry, ..report(&fish) };
+        QualityRules::default().judge(&mut report);
+        assert_eq!(checks(&report), [(Check::Sharpness, Severity::Warn)]);
+    }
+}
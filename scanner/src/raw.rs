@@ -0,0 +1,700 @@
+//! Camera raw files (Canon CR2 and DNG): decoding the sensor data, white balance from the
+//! file's metadata, demosaicing and conversion to sRGB.
+
+use image::{
+    DynamicImage,
+    ImageBuffer,
+    Rgb,
+};
+
+use crate::{
+    color::linear_to_srgb16,
+    error::ScanError,
+    input::Rgb16Image,
+    ljpeg,
+};
+
+use std::{
+    fs,
+    path::Path,
+};
+
+// tiff tags
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const MODEL: u16 = 272;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const SUB_IFDS: u16 = 330;
+const CFA_REPEAT_PATTERN_DIM: u16 = 33421;
+const CFA_PATTERN: u16 = 33422;
+const EXIF_IFD: u16 = 34665;
+const MAKER_NOTE: u16 = 37500;
+const LINEARIZATION_TABLE: u16 = 50712;
+const BLACK_LEVEL_REPEAT_DIM: u16 = 50713;
+const BLACK_LEVEL: u16 = 50714;
+const WHITE_LEVEL: u16 = 50717;
+const DEFAULT_CROP_ORIGIN: u16 = 50719;
+const DEFAULT_CROP_SIZE: u16 = 50720;
+const COLOR_MATRIX_1: u16 = 50721;
+const COLOR_MATRIX_2: u16 = 50722;
+const AS_SHOT_NEUTRAL: u16 = 50728;
+const ACTIVE_AREA: u16 = 50829;
+// canon tags
+const CR2_SLICES: u16 = 0xC640;
+const CANON_SENSOR_INFO: u16 = 0x00E0;
+const CANON_COLOR_DATA: u16 = 0x4001;
+
+const PHOTOMETRIC_CFA: f64 = 32803.0;
+const PHOTOMETRIC_LINEAR_RAW: f64 = 34892.0;
+
+/// sRGB primaries (D65) to XYZ
+const XYZ_FROM_SRGB: [[f32; 3]; 3] = [
+    [0.412453, 0.357580, 0.180423],
+    [0.212671, 0.715160, 0.072169],
+    [0.019334, 0.119193, 0.950227],
+];
+
+/// XYZ to camera rgb (times 10000) of cameras whose raw files don't say, from Adobe's DNG converter
+const CAMERA_MATRICES: &[(&str, [i32; 9])] = &[
+    ("Canon EOS 600D", [6461, -907, -882, -4300, 12184, 2378, -819, 1944, 5931]),
+    ("Canon EOS REBEL T3i", [6461, -907, -882, -4300, 12184, 2378, -819, 1944, 5931]),
+    ("Canon EOS Kiss X5", [6461, -907, -882, -4300, 12184, 2378, -819, 1944, 5931]),
+];
+
+/// true for the raw formats the scanner decodes itself
+pub fn is_raw_file<P: AsRef<Path>>(path: P) -> bool {
+    match path.as_ref().extension().and_then(|e| e.to_str()) {
+        Some(ext) => ["cr2", "dng"].contains(&ext.to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Opens a CR2 or DNG file and develops it into a 16 bit sRGB image.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<DynamicImage, ScanError> {
+    decode(&fs::read(path)?)
+}
+
+/// Develops the raw file in `data`: demosaiced, white balanced as shot and converted to sRGB.
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ScanError> {
+    let tiff = Tiff::new(data).ok_or_else(|| raw_error("not a tiff based raw file"))?;
+    let sensor = if data.get(8..10) == Some(b"CR") { read_cr2(&tiff)? } else { read_dng(&tiff)? };
+    Ok(DynamicImage::ImageRgb16(sensor.develop()))
+}
+
+fn raw_error(message: &str) -> ScanError {
+    ScanError::Raw(message.to_string())
+}
+
+/// One entry of an image file directory.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: usize,
+    /// where the values are, inline in the entry or elsewhere in the file
+    offset: usize,
+}
+
+struct Ifd {
+    entries: Vec<Entry>,
+    next: usize,
+}
+
+/// Just enough tiff to find the sensor data and its metadata.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Tiff<'a>> {
+        let big_endian = match data.get(0..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    fn bytes(&self, offset: usize, length: usize) -> Result<&'a [u8], ScanError> {
+        offset.checked_add(length)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| raw_error("offset beyond the end of the file"))
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ScanError> {
+        let b = self.bytes(offset, 2)?;
+        Ok(if self.big_endian { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ScanError> {
+        let b = self.bytes(offset, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn first_ifd(&self) -> Result<usize, ScanError> {
+        Ok(self.u32(4)? as usize)
+    }
+
+    fn ifd(&self, offset: usize) -> Result<Ifd, ScanError> {
+        let count = self.u16(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + 2 + i * 12;
+            let kind = self.u16(at + 2)?;
+            let count = self.u32(at + 4)? as usize;
+            let size = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 => 8,
+                _ => 0,
+            };
+            let offset = if size * count <= 4 { at + 8 } else { self.u32(at + 8)? as usize };
+            // the count comes from the file, so the values have to fit in it before anything trusts it
+            if size.checked_mul(count).and_then(|n| n.checked_add(offset)).is_none_or(|end| end > self.data.len()) {
+                return Err(raw_error("tiff values beyond the end of the file"));
+            }
+            entries.push(Entry { tag: self.u16(at)?, kind, count, offset });
+        }
+        let next = self.u32(offset + 2 + count * 12).unwrap_or(0) as usize;
+        Ok(Ifd { entries, next })
+    }
+
+    /// every ifd of the chain starting at `offset`, with their sub ifds
+    fn ifds(&self, mut offset: usize) -> Result<Vec<Ifd>, ScanError> {
+        let mut ifds = Vec::new();
+        while offset != 0 && ifds.len() < 32 {
+            let ifd = self.ifd(offset)?;
+            offset = ifd.next;
+            let sub_ifds = self.values(&ifd, SUB_IFDS)?;
+            ifds.push(ifd);
+            for sub in sub_ifds.unwrap_or_default() {
+                ifds.push(self.ifd(sub as usize)?);
+            }
+        }
+        Ok(ifds)
+    }
+
+    fn entry(&self, ifd: &Ifd, tag: u16) -> Option<Entry> {
+        ifd.entries.iter().find(|e| e.tag == tag).copied()
+    }
+
+    /// the values of `tag` as numbers, whatever their type
+    fn values(&self, ifd: &Ifd, tag: u16) -> Result<Option<Vec<f64>>, ScanError> {
+        let entry = match self.entry(ifd, tag) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let mut values = Vec::with_capacity(entry.count);
+        for i in 0..entry.count {
+            let value = match entry.kind {
+                1 | 2 | 7 => self.bytes(entry.offset + i, 1)?[0] as f64,
+                6 => self.bytes(entry.offset + i, 1)?[0] as i8 as f64,
+                3 => self.u16(entry.offset + i * 2)? as f64,
+                8 => self.u16(entry.offset + i * 2)? as i16 as f64,
+                4 | 13 => self.u32(entry.offset + i * 4)? as f64,
+                9 => self.u32(entry.offset + i * 4)? as i32 as f64,
+                11 => f32::from_bits(self.u32(entry.offset + i * 4)?) as f64,
+                5 => self.u32(entry.offset + i * 8)? as f64 / self.u32(entry.offset + i * 8 + 4)?.max(1) as f64,
+                10 => self.u32(entry.offset + i * 8)? as i32 as f64 / (self.u32(entry.offset + i * 8 + 4)? as i32).max(1) as f64,
+                12 => {
+                    let b = self.bytes(entry.offset + i * 8, 8)?;
+                    let b = [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]];
+                    if self.big_endian { f64::from_be_bytes(b) } else { f64::from_le_bytes(b) }
+                }
+                _ => return Err(raw_error("unknown tiff value type")),
+            };
+            values.push(value);
+        }
+        Ok(Some(values))
+    }
+
+    fn number(&self, ifd: &Ifd, tag: u16) -> Result<Option<f64>, ScanError> {
+        Ok(self.values(ifd, tag)?.and_then(|values| values.first().copied()))
+    }
+
+    fn required(&self, ifd: &Ifd, tag: u16) -> Result<Vec<f64>, ScanError> {
+        self.values(ifd, tag)?.ok_or_else(|| ScanError::Raw(format!("tiff tag {} is missing", tag)))
+    }
+
+    /// the first value of a tag that has to be there
+    fn required_number(&self, ifd: &Ifd, tag: u16) -> Result<f64, ScanError> {
+        self.required(ifd, tag)?.first().copied().ok_or_else(|| ScanError::Raw(format!("tiff tag {} is empty", tag)))
+    }
+
+    fn text(&self, ifd: &Ifd, tag: u16) -> Option<String> {
+        let entry = self.entry(ifd, tag)?;
+        let bytes = self.bytes(entry.offset, entry.count).ok()?;
+        Some(String::from_utf8_lossy(bytes).trim_end_matches('\0').trim().to_string())
+    }
+}
+
+/// The sensor data and everything needed to develop it.
+struct Sensor {
+    width: usize,
+    height: usize,
+    /// 1 for a color filter array, 3 for raw data that is already demosaiced (linear DNG)
+    samples: usize,
+    data: Vec<u16>,
+    /// color (0 red, 1 green, 2 blue) of the 2x2 filter pattern at the origin, row by row
+    cfa: [usize; 4],
+    /// per position of the filter pattern
+    black: [f32; 4],
+    white: f32,
+    /// white balance multipliers of camera red, green and blue
+    balance: [f32; 3],
+    /// camera rgb to linear sRGB, `None` if unknown
+    to_srgb: Option<[[f32; 3]; 3]>,
+}
+
+impl Sensor {
+    /// Cuts out the part of the sensor that's part of the picture, keeping the filter pattern aligned.
+    fn crop(&mut self, left: usize, top: usize, width: usize, height: usize) -> Result<(), ScanError> {
+        if left + width > self.width || top + height > self.height || width < 2 || height < 2 {
+            return Err(raw_error("crop area outside of the sensor"));
+        }
+        let mut data = Vec::with_capacity(width * height * self.samples);
+        for y in top..top + height {
+            let row = (y * self.width + left) * self.samples;
+            data.extend_from_slice(&self.data[row..row + width * self.samples]);
+        }
+        self.cfa = shift_pattern(self.cfa, left, top);
+        self.black = shift_pattern(self.black, left, top);
+        self.data = data;
+        self.width = width;
+        self.height = height;
+        Ok(())
+    }
+
+    /// Black and white level, white balance, demosaicing, color matrix and sRGB gamma.
+    fn develop(&self) -> Rgb16Image {
+        let (width, height) = (self.width, self.height);
+        let min_balance = self.balance.iter().cloned().fold(f32::MAX, f32::min).max(1e-6);
+        let balance = self.balance.map(|b| b / min_balance);
+        // white balanced and clipped, so blown out highlights end up white instead of pink
+        let normalize = |value: u16, position: usize, color: usize| {
+            let black = self.black[position];
+            ((value as f32 - black) / (self.white - black).max(1.0) * balance[color]).clamp(0.0, 1.0)
+        };
+        let position = |x: usize, y: usize| (y % 2) * 2 + x % 2;
+        let linear: Vec<f32> = if self.samples == 3 {
+            self.data.iter().enumerate().map(|(i, &v)| {
+                let pixel = i / 3;
+                normalize(v, position(pixel % width, pixel / width), i % 3)
+            }).collect()
+        } else {
+            self.data.iter().enumerate().map(|(i, &v)| {
+                let p = position(i % width, i / width);
+                normalize(v, p, self.cfa[p])
+            }).collect()
+        };
+        let camera_rgb = |x: usize, y: usize| -> [f32; 3] {
+            if self.samples == 3 {
+                let i = (y * width + x) * 3;
+                return [linear[i], linear[i + 1], linear[i + 2]];
+            }
+            // bilinear: each missing color is the average of its neighbours in the 3x3 window
+            let mut sum = [0f32; 3];
+            let mut count = [0u32; 3];
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let color = self.cfa[position(nx, ny)];
+                    sum[color] += linear[ny * width + nx];
+                    count[color] += 1;
+                }
+            }
+            let own = self.cfa[position(x, y)];
+            [0, 1, 2].map(|c| if c == own {
+                linear[y * width + x]
+            } else {
+                sum[c] / count[c].max(1) as f32
+            })
+        };
+        ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+            let camera = camera_rgb(x as usize, y as usize);
+            let rgb = match &self.to_srgb {
+                Some(m) => [0, 1, 2].map(|r| m[r][0] * camera[0] + m[r][1] * camera[1] + m[r][2] * camera[2]),
+                None => camera,
+            };
+            Rgb(rgb.map(linear_to_srgb16))
+        })
+    }
+}
+
+/// the 2x2 pattern as seen from `(left, top)`
+fn shift_pattern<T: Copy>(pattern: [T; 4], left: usize, top: usize) -> [T; 4] {
+    [0, 1, 2, 3].map(|i| pattern[((i / 2 + top) % 2) * 2 + (i % 2 + left) % 2])
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    [0, 1, 2].map(|r| [0, 1, 2].map(|c| (0..3).map(|k| a[r][k] * b[k][c]).sum()))
+}
+
+fn invert(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    Some([0, 1, 2].map(|r| [0, 1, 2].map(|c| cofactor(c, r) / det)))
+}
+
+/// Camera rgb to linear sRGB, from a matrix that maps XYZ to camera rgb.
+/// The rows are scaled so white balanced white stays white.
+fn camera_to_srgb(xyz_to_camera: &[f32]) -> Option<[[f32; 3]; 3]> {
+    if xyz_to_camera.len() != 9 {
+        return None;
+    }
+    let m = [0, 1, 2].map(|r| [0, 1, 2].map(|c| xyz_to_camera[r * 3 + c]));
+    let mut srgb_to_camera = multiply(&m, &XYZ_FROM_SRGB);
+    for row in &mut srgb_to_camera {
+        let sum: f32 = row.iter().sum();
+        if sum.abs() < 1e-6 {
+            return None;
+        }
+        *row = row.map(|v| v / sum);
+    }
+    invert(&srgb_to_camera)
+}
+
+/// The raw image of a DNG: the full resolution one with a color filter array or linear data.
+fn read_dng(tiff: &Tiff) -> Result<Sensor, ScanError> {
+    let ifds = tiff.ifds(tiff.first_ifd()?)?;
+    let raw = ifds.iter()
+        .filter(|ifd| tiff.number(ifd, NEW_SUBFILE_TYPE).ok().flatten().unwrap_or(0.0) == 0.0)
+        .find(|ifd| matches!(
+            tiff.number(ifd, PHOTOMETRIC).ok().flatten(),
+            Some(p) if p == PHOTOMETRIC_CFA || p == PHOTOMETRIC_LINEAR_RAW
+        ))
+        .ok_or_else(|| raw_error("no raw image in the dng"))?;
+    let main = &ifds[0];
+
+    let width = tiff.required_number(raw, IMAGE_WIDTH)? as usize;
+    let height = tiff.required_number(raw, IMAGE_LENGTH)? as usize;
+    let samples = tiff.number(raw, SAMPLES_PER_PIXEL)?.unwrap_or(1.0) as usize;
+    let bits = tiff.number(raw, BITS_PER_SAMPLE)?.unwrap_or(16.0) as u32;
+    if samples != 1 && samples != 3 {
+        return Err(ScanError::Raw(format!("{} samples per pixel are not supported", samples)));
+    }
+    let mut data = read_image_data(tiff, raw, width, height, samples, bits)?;
+    if let Some(table) = tiff.values(raw, LINEARIZATION_TABLE)? {
+        if !table.is_empty() {
+            for value in &mut data {
+                *value = table[(*value as usize).min(table.len() - 1)] as u16;
+            }
+        }
+    }
+
+    let cfa = if samples == 1 {
+        let dim = tiff.required(raw, CFA_REPEAT_PATTERN_DIM)?;
+        let pattern = tiff.required(raw, CFA_PATTERN)?;
+        if dim != [2.0, 2.0] || pattern.len() != 4 || pattern.iter().any(|&c| c > 2.0) {
+            return Err(raw_error("only 2x2 rgb color filter arrays are supported"));
+        }
+        [0, 1, 2, 3].map(|i| pattern[i] as usize)
+    } else {
+        [0, 1, 1, 2]
+    };
+    let black = match tiff.values(raw, BLACK_LEVEL)? {
+        Some(levels) => {
+            let dim = tiff.values(raw, BLACK_LEVEL_REPEAT_DIM)?.unwrap_or_else(|| vec![1.0, 1.0]);
+            match (levels.len(), dim.as_slice()) {
+                (1, _) => [levels[0] as f32; 4],
+                (4, [rows, columns]) if *rows == 2.0 && *columns == 2.0 => [0, 1, 2, 3].map(|i| levels[i] as f32),
+                // one level per sample of linear raw data, the average is close enough
+                (n, _) => [(levels.iter().sum::<f64>() / n as f64) as f32; 4],
+            }
+        }
+        None => [0.0; 4],
+    };
+    let white = tiff.number(raw, WHITE_LEVEL)?.map(|w| w as f32).unwrap_or(((1u32 << bits.min(16)) - 1) as f32);
+    let balance = match tiff.values(main, AS_SHOT_NEUTRAL)? {
+        Some(neutral) if neutral.len() == 3 && neutral.iter().all(|&n| n > 0.0) => [0, 1, 2].map(|c| (1.0 / neutral[c]) as f32),
+        _ => [1.0; 3],
+    };
+    // the second matrix is usually the daylight one
+    let matrix = match tiff.values(main, COLOR_MATRIX_2)? {
+        Some(matrix) => Some(matrix),
+        None => tiff.values(main, COLOR_MATRIX_1)?,
+    };
+    let to_srgb = matrix.and_then(|m| camera_to_srgb(&m.iter().map(|&v| v as f32).collect::<Vec<_>>()));
+
+    let mut sensor = Sensor { width, height, samples, data, cfa, black, white, balance, to_srgb };
+    // the default crop is relative to the active area
+    if let Some(area) = tiff.values(raw, ACTIVE_AREA)? {
+        if area.len() == 4 {
+            let [top, left, bottom, right] = [0, 1, 2, 3].map(|i| area[i] as usize);
+            sensor.crop(left, top, right.saturating_sub(left), bottom.saturating_sub(top))?;
+        }
+    }
+    if let (Some(origin), Some(size)) = (tiff.values(raw, DEFAULT_CROP_ORIGIN)?, tiff.values(raw, DEFAULT_CROP_SIZE)?) {
+        if origin.len() == 2 && size.len() == 2 {
+            sensor.crop(origin[0] as usize, origin[1] as usize, size[0] as usize, size[1] as usize)?;
+        }
+    }
+    Ok(sensor)
+}
+
+/// Reads uncompressed or lossless jpeg compressed image data, from strips or tiles.
+fn read_image_data(tiff: &Tiff, ifd: &Ifd, width: usize, height: usize, samples: usize, bits: u32) -> Result<Vec<u16>, ScanError> {
+    let compression = tiff.number(ifd, COMPRESSION)?.unwrap_or(1.0) as u32;
+    let (tile_width, tile_height, offsets, counts) = match tiff.values(ifd, TILE_OFFSETS)? {
+        Some(offsets) => (
+            tiff.required_number(ifd, TILE_WIDTH)? as usize,
+            tiff.required_number(ifd, TILE_LENGTH)? as usize,
+            offsets,
+            tiff.required(ifd, TILE_BYTE_COUNTS)?,
+        ),
+        None => (
+            width,
+            tiff.number(ifd, ROWS_PER_STRIP)?.map(|r| r as usize).unwrap_or(height).min(height),
+            tiff.required(ifd, STRIP_OFFSETS)?,
+            tiff.required(ifd, STRIP_BYTE_COUNTS)?,
+        ),
+    };
+    if tile_width == 0 || tile_height == 0 || offsets.len() != counts.len() {
+        return Err(raw_error("broken strip or tile layout"));
+    }
+    // every sample takes at least one bit of the file, compressed or not, so a header
+    // asking for more is forged or broken and mustn't get its memory
+    let bits_per_sample = if compression == 1 { bits.max(1) as usize } else { 1 };
+    let fits = |w: usize, h: usize| {
+        w.checked_mul(h)
+            .and_then(|n| n.checked_mul(samples))
+            .and_then(|n| n.checked_mul(bits_per_sample))
+            .is_some_and(|needed| needed <= tiff.data.len().saturating_mul(8))
+    };
+    if !fits(width, height) || !fits(tile_width, tile_height) {
+        return Err(raw_error("the image is larger than the file"));
+    }
+    let mut data = vec![0u16; width * height * samples];
+    let tiles_across = width.div_ceil(tile_width);
+    for (i, (&offset, &count)) in offsets.iter().zip(counts.iter()).enumerate() {
+        let bytes = tiff.bytes(offset as usize, count as usize)?;
+        let (tile_left, tile_top) = (i % tiles_across * tile_width, i / tiles_across * tile_height);
+        let tile_stride = tile_width * samples;
+        let tile: Vec<u16> = match compression {
+            1 => unpack(bytes, bits, tile_stride * tile_height, tiff.big_endian)?,
+            7 => {
+                let jpeg = ljpeg::decode(bytes).map_err(ScanError::Raw)?;
+                // the jpeg frame can be narrower and have more components than the tile
+                let jpeg_stride = jpeg.width * jpeg.components;
+                let mut tile = vec![0u16; tile_stride * tile_height];
+                for (row, values) in jpeg.data.chunks(jpeg_stride).take(tile_height).enumerate() {
+                    let n = values.len().min(tile_stride);
+                    tile[row * tile_stride..row * tile_stride + n].copy_from_slice(&values[..n]);
+                }
+                tile
+            }
+            _ => return Err(ScanError::Raw(format!("compression {} is not supported", compression))),
+        };
+        for row in 0..tile_height.min(height.saturating_sub(tile_top)) {
+            let n = tile_width.min(width.saturating_sub(tile_left)) * samples;
+            let to = ((tile_top + row) * width + tile_left) * samples;
+            let from = row * tile_stride;
+            let values = tile.get(from..from + n).ok_or_else(|| raw_error("truncated strip or tile"))?;
+            data[to..to + n].copy_from_slice(values);
+        }
+    }
+    Ok(data)
+}
+
+/// Uncompressed samples: 16 bit in the file's byte order, or packed big endian bits.
+fn unpack(bytes: &[u8], bits: u32, count: usize, big_endian: bool) -> Result<Vec<u16>, ScanError> {
+    if bits == 16 {
+        let count = count.min(bytes.len() / 2);
+        return Ok((0..count).map(|i| {
+            let b = [bytes[i * 2], bytes[i * 2 + 1]];
+            if big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) }
+        }).collect());
+    }
+    if bits == 0 || bits > 16 {
+        return Err(ScanError::Raw(format!("{} bits per sample are not supported", bits)));
+    }
+    let count = count.min(bytes.len() * 8 / bits as usize);
+    let mut values = Vec::with_capacity(count);
+    let (mut buffer, mut buffered) = (0u32, 0u32);
+    let mut bytes = bytes.iter();
+    for _ in 0..count {
+        while buffered < bits {
+            buffer = (buffer << 8) | *bytes.next().unwrap_or(&0) as u32;
+            buffered += 8;
+        }
+        values.push(((buffer >> (buffered - bits)) & ((1 << bits) - 1)) as u16);
+        buffered -= bits;
+    }
+    Ok(values)
+}
+
+/// The raw image of a CR2: lossless jpeg in the fourth ifd, with the metadata in Canon's maker note.
+fn read_cr2(tiff: &Tiff) -> Result<Sensor, ScanError> {
+    let mut ifds = Vec::new();
+    let mut offset = tiff.first_ifd()?;
+    while offset != 0 && ifds.len() < 8 {
+        let ifd = tiff.ifd(offset)?;
+        offset = ifd.next;
+        ifds.push(ifd);
+    }
+    let main = ifds.first().ok_or_else(|| raw_error("empty cr2"))?;
+    let raw = ifds.get(3).ok_or_else(|| raw_error("no raw image in the cr2"))?;
+    let strip = tiff.required_number(raw, STRIP_OFFSETS)? as usize;
+    let length = tiff.required_number(raw, STRIP_BYTE_COUNTS)? as usize;
+    let jpeg = ljpeg::decode(tiff.bytes(strip, length)?).map_err(ScanError::Raw)?;
+    let height = jpeg.height;
+    let total = jpeg.width * jpeg.components * height;
+
+    // the jpeg rows are cut into vertical slices: n slices of one width, then the last one
+    let slices = tiff.values(raw, CR2_SLICES)?.filter(|s| s.len() == 3 && s[0] + s[1] + s[2] > 0.0)
+        .filter(|s| s[0] <= (jpeg.width * jpeg.components) as f64);
+    let (width, data) = match slices {
+        Some(s) => {
+            let widths: Vec<usize> = (0..s[0] as usize).map(|_| s[1] as usize).chain(Some(s[2] as usize)).collect();
+            let width: usize = widths.iter().sum();
+            if width * height != total {
+                return Err(raw_error("cr2 slices don't match the raw size"));
+            }
+            let mut data = vec![0u16; total];
+            let mut source = jpeg.data.iter();
+            let mut slice_left = 0;
+            for slice_width in widths {
+                for y in 0..height {
+                    for x in slice_left..slice_left + slice_width {
+                        data[y * width + x] = *source.next().unwrap();
+                    }
+                }
+                slice_left += slice_width;
+            }
+            (width, data)
+        }
+        None => (jpeg.width * jpeg.components, jpeg.data),
+    };
+
+    let maker_note = match tiff.number(main, EXIF_IFD)? {
+        Some(exif) => {
+            let exif = tiff.ifd(exif as usize)?;
+            match tiff.entry(&exif, MAKER_NOTE) {
+                Some(entry) => Some(tiff.ifd(entry.offset)?),
+                None => None,
+            }
+        }
+        None => None,
+    };
+    let values = |tag| -> Result<Option<Vec<f64>>, ScanError> {
+        match &maker_note {
+            Some(note) => tiff.values(note, tag),
+            None => Ok(None),
+        }
+    };
+    // inclusive borders of the exposed part of the sensor, left of it are covered columns
+    let borders = values(CANON_SENSOR_INFO)?.filter(|info| info.len() > 8)
+        .map(|info| [5, 6, 7, 8].map(|i| info[i] as usize))
+        .filter(|&[left, top, right, bottom]| left < right && top < bottom && right < width && bottom < height);
+    let black = match borders {
+        Some([left, ..]) if left > 8 => {
+            let mut sum = 0f64;
+            for y in 0..height {
+                sum += data[y * width + 4..y * width + left - 4].iter().map(|&v| v as f64).sum::<f64>();
+            }
+            (sum / ((left - 8) * height) as f64) as f32
+        }
+        _ => 0.0,
+    };
+    // the white level isn't in the file. If enough pixels share the brightest value the
+    // photo is clipped there, otherwise it's the top of the jpeg's range
+    let brightest = data.iter().copied().max().unwrap_or(0);
+    let clipped = data.iter().filter(|&&v| v as u32 * 100 >= brightest as u32 * 99).count();
+    let white = if clipped * 10_000 > data.len() {
+        brightest as f32 * 0.99
+    } else {
+        ((1u32 << jpeg.precision) - 1) as f32
+    };
+    // the white balance levels as shot are at a different place in each version of the color data
+    let balance = match values(CANON_COLOR_DATA)? {
+        Some(color) => {
+            let at = match color.len() {
+                582 => 25,
+                653 => 34,
+                _ => 63,
+            };
+            match color.get(at..at + 4) {
+                Some(rggb) if rggb.iter().all(|&l| l > 0.0) => [rggb[0] as f32, ((rggb[1] + rggb[2]) / 2.0) as f32, rggb[3] as f32],
+                _ => [1.0; 3],
+            }
+        }
+        None => [1.0; 3],
+    };
+    let model = tiff.text(main, MODEL).unwrap_or_default();
+    let to_srgb = CAMERA_MATRICES.iter()
+        .find(|(name, _)| *name == model)
+        .and_then(|(_, m)| camera_to_srgb(&m.map(|v| v as f32 / 10_000.0)));
+
+    let mut sensor = Sensor {
+        width,
+        height,
+        samples: 1,
+        data,
+        // Canon sensors start with a red pixel
+        cfa: [0, 1, 1, 2],
+        black: [black; 4],
+        white,
+        balance,
+        to_srgb,
+    };
+    if let Some([left, top, right, bottom]) = borders {
+        sensor.crop(left, top, right - left + 1, bottom - top + 1)?;
+    }
+    Ok(sensor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a little endian tiff with one ifd of (tag, type, count, value) entries
+    fn tiff(entries: &[(u16, u16, u32, u32)]) -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for &(tag, kind, count, value) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&kind.to_le_bytes());
+            data.extend_from_slice(&count.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn empty_required_tag() {
+        let data = tiff(&[(PHOTOMETRIC, 3, 1, PHOTOMETRIC_CFA as u32), (IMAGE_WIDTH, 3, 0, 0), (IMAGE_LENGTH, 3, 1, 16)]);
+        assert!(matches!(decode(&data), Err(ScanError::Raw(message)) if message.contains("empty")));
+    }
+
+    #[test]
+    fn counts_beyond_the_file() {
+        for count in [0x10_0000, u32::MAX] {
+            let data = tiff(&[(PHOTOMETRIC, 3, 1, PHOTOMETRIC_CFA as u32), (SUB_IFDS, 4, count, 8)]);
+            assert!(matches!(decode(&data), Err(ScanError::Raw(_))));
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! The fish styler from the ideas file: a small web app for the installation machine where
+//! the operator takes (or uploads) a photo, clicks on the backdrop to pick its color, checks
+//! the mask and normal map, sets the fish's scale and swarm factor and publishes it to `sea`.
+//!
+//! Everything, the page included, is served by the scanner itself, no internet needed.
+//! There is one photo at a time, the styler is meant for a single operator.
+
+use image::{
+    imageops::FilterType,
+    DynamicImage,
+    GenericImageView,
+    ImageBuffer,
+    ImageOutputFormat,
+    Luma,
+    Rgb,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use serde_json::json;
+
+use tiny_http::{
+    Header,
+    Method,
+    Request,
+    Response,
+    Server,
+};
+
+use crate::{
+    capture::{
+        CaptureError,
+        CaptureSource,
+    },
+    fish_skin_from_image,
+    input::{
+        open_image,
+        to_u8,
+        Photo,
+        Rgb16Image,
+    },
+    keyed_colors,
+    manifest::FishManifest,
+    options::{
+        Background,
+        ScanOptions,
+    },
+    output::{
+        is_image_file,
+        next_fish_number,
+        publish,
+    },
+    FishSkin,
+    ScanError,
+};
+
+use std::{
+    fs,
+    io::{
+        self,
+        Read,
+    },
+    net::{
+        IpAddr,
+        Ipv6Addr,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+const PAGE: &str = include_str!("styler.html");
+
+/// longest side of the photo as shown in the browser
+const PREVIEW_SIZE: u32 = 1280;
+
+/// the eyedropper averages a square of this many pixels around the click, photos are noisy
+const PICK_RADIUS: u32 = 3;
+
+/// largest upload, a raw file from a high resolution camera fits
+const MAX_BODY_SIZE: u64 = 200 * 1024 * 1024;
+
+/// The photo being styled.
+struct Session {
+    photo: PathBuf,
+    image: DynamicImage,
+    /// the photo scaled down to `PREVIEW_SIZE`, as jpeg
+    preview: Vec<u8>,
+    options: ScanOptions,
+    /// the last scan, with the error if the fish couldn't be cut out
+    skin: Result<FishSkin, ScanError>,
+    /// kept across scans, so picking another color doesn't undo the operator's edits
+    manifest: Option<FishManifest>,
+    /// the fish number once published, publishing again replaces that fish
+    published: Option<u32>,
+}
+
+/// The part of the manifest the operator can change, fields that are left out stay as they are.
+#[derive(Debug, Deserialize)]
+struct ManifestEdit {
+    scale: Option<[f32; 3]>,
+    swarm_factor: Option<f32>,
+}
+
+/// A request that went wrong, answered with `status` and `{"error": message}`.
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: &str) -> Failure {
+        Failure { status, message: message.to_string() }
+    }
+}
+
+impl From<ScanError> for Failure {
+    fn from(err: ScanError) -> Self {
+        let status = match err {
+            ScanError::Io(_) => 500,
+            _ => 400,
+        };
+        Failure { status, message: err.to_string() }
+    }
+}
+
+impl From<CaptureError> for Failure {
+    fn from(err: CaptureError) -> Self {
+        Failure { status: 503, message: err.to_string() }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure { status: 500, message: err.to_string() }
+    }
+}
+
+/// What `GET /state` and every change answer with.
+#[derive(Serialize)]
+struct State<'a> {
+    /// whether photos can be taken, or only uploaded
+    capture: bool,
+    photo: Option<PhotoInfo>,
+    /// the keyed out color and distance of the last scan
+    background: Option<[u8; 3]>,
+    key_distance: Option<f32>,
+    /// why the last scan failed
+    error: Option<String>,
+    /// the quality checks of the last scan that only warned
+    warnings: Vec<String>,
+    manifest: Option<&'a FishManifest>,
+    published: Option<u32>,
+}
+
+/// the size is needed to turn clicks on the scaled down photo into photo pixels
+#[derive(Serialize)]
+struct PhotoInfo {
+    name: String,
+    width: u32,
+    height: u32,
+}
+
+enum Reply {
+    Json(String),
+    Data(Vec<u8>, &'static str),
+}
+
+pub struct Styler {
+    /// where captured and uploaded photos are kept
+    photo_dir: PathBuf,
+    /// where published fish go, `sea`'s fish folder
+    out_dir: PathBuf,
+    /// what every photo is scanned with, until the operator picks a background color
+    options: ScanOptions,
+    capture: Option<Box<dyn CaptureSource>>,
+    /// how long to wait for the camera
+    pub timeout: Duration,
+    session: Option<Session>,
+}
+
+impl Styler {
+    /// Without a `capture` source, photos can only be uploaded. `options.multiple_fish` is refused,
+    /// the page has room for one fish.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        photo_dir: P,
+        out_dir: Q,
+        options: ScanOptions,
+        capture: Option<Box<dyn CaptureSource>>,
+    ) -> io::Result<Styler> {
+        if options.multiple_fish {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the styler shows one fish per photo, several fish can't be styled"));
+        }
+        let photo_dir = photo_dir.as_ref().to_path_buf();
+        let out_dir = out_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&photo_dir)?;
+        fs::create_dir_all(&out_dir)?;
+        Ok(Styler { photo_dir, out_dir, options, capture, timeout: Duration::from_secs(30), session: None })
+    }
+
+    /// Answers requests on `address` (e.g. `127.0.0.1:8080`) until the server fails.
+    pub fn serve(&mut self, address: &str) -> io::Result<()> {
+        let server = Server::http(address).map_err(|err| io::Error::other(err.to_string()))?;
+        for mut request in server.incoming_requests() {
+            let reply = self.handle(&mut request);
+            let response = match reply {
+                Ok(Reply::Json(json)) => data_response(200, json.into_bytes(), "application/json"),
+                Ok(Reply::Data(data, content_type)) => data_response(200, data, content_type),
+                Err(failure) => data_response(failure.status, json!({ "error": failure.message }).to_string().into_bytes(), "application/json"),
+            };
+            // the browser may have gone away in the meantime, that's its business
+            let _ = request.respond(response);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: &mut Request) -> Result<Reply, Failure> {
+        let url = request.url().to_string();
+        let (path, query) = match url.find('?') {
+            Some(question) => (&url[..question], &url[question + 1..]),
+            None => (url.as_str(), ""),
+        };
+        check_origin(request)?;
+        if request.body_length().is_some_and(|length| length as u64 > MAX_BODY_SIZE) {
+            return Err(Failure::new(413, "the upload is too big"));
+        }
+        let mut body = Vec::new();
+        request.as_reader().take(MAX_BODY_SIZE + 1).read_to_end(&mut body)?;
+        if body.len() as u64 > MAX_BODY_SIZE {
+            return Err(Failure::new(413, "the upload is too big"));
+        }
+        match (request.method(), path) {
+            (Method::Get, "/") => Ok(Reply::Data(PAGE.as_bytes().to_vec(), "text/html; charset=utf-8")),
+            (Method::Get, "/state") => self.state(),
+            (Method::Post, "/capture") => {
+                let source = self.capture.as_mut().ok_or_else(|| Failure::new(404, "no camera, start the styler with --camera or --replay"))?;
+                let photo = source.capture(&self.photo_dir, self.timeout)?;
+                self.load(photo)?;
+                self.state()
+            }
+            (Method::Post, "/photo") => {
+                let name = query_value(query, "name").unwrap_or_else(|| "upload.jpg".to_string());
+                let photo = self.photo_dir.join(upload_name(&name));
+                if !is_image_file(&photo) {
+                    return Err(Failure::new(400, &format!("{} is not an image file", name)));
+                }
+                fs::write(&photo, &body)?;
+                self.load(photo)?;
+                self.state()
+            }
+            (Method::Get, "/photo.jpg") => Ok(Reply::Data(self.session()?.preview.clone(), "image/jpeg")),
+            (Method::Post, "/background") => {
+                self.pick_background(query)?;
+                self.state()
+            }
+            (Method::Get, "/preview/colors.png") => self.preview(|skin| DynamicImage::ImageRgba8(skin.colors.clone())),
+            (Method::Get, "/preview/mask.png") => self.preview(|skin| {
+                let (width, height) = skin.colors.dimensions();
+                DynamicImage::ImageLuma8(ImageBuffer::from_fn(width, height, |x, y| Luma([skin.colors.get_pixel(x, y)[3]])))
+            }),
+            (Method::Get, "/preview/normals.png") => self.preview(|skin| DynamicImage::ImageRgb8(skin.normals.to_rgb8())),
+            (Method::Post, "/manifest") => {
+                let edit: ManifestEdit = serde_json::from_slice(&body).map_err(|err| Failure::new(400, &err.to_string()))?;
+                self.edit_manifest(edit)?;
+                self.state()
+            }
+            (Method::Post, "/publish") => {
+                self.publish()?;
+                self.state()
+            }
+            _ => Err(Failure::new(404, &format!("no such page: {}", path))),
+        }
+    }
+
+    fn session(&self) -> Result<&Session, Failure> {
+        self.session.as_ref().ok_or_else(|| Failure::new(409, "take or upload a photo first"))
+    }
+
+    fn session_mut(&mut self) -> Result<&mut Session, Failure> {
+        self.session.as_mut().ok_or_else(|| Failure::new(409, "take or upload a photo first"))
+    }
+
+    /// Starts over with the photo at `photo` and scans it with the styler's options.
+    fn load(&mut self, photo: PathBuf) -> Result<(), Failure> {
+        let image = open_image(&photo)?;
+        let mut preview = Vec::new();
+        DynamicImage::ImageRgb8(image.resize(PREVIEW_SIZE, PREVIEW_SIZE, FilterType::Triangle).to_rgb8())
+            .write_to(&mut preview, ImageOutputFormat::Jpeg(90))
+            .map_err(ScanError::from)?;
+        let mut options = self.options.clone();
+        // every photo gets its own stages, like in batch mode
+        if let (Some(dir), Some(name)) = (&self.options.debug_dir, photo.file_name()) {
+            options.debug_dir = Some(dir.join(name));
+        }
+        let skin = fish_skin_from_image(image.clone(), &options);
+        let manifest = skin.as_ref().ok().map(|skin| FishManifest::new(skin, Some(&photo)));
+        self.session = Some(Session { photo, image, preview, options, skin, manifest, published: None });
+        Ok(())
+    }
+
+    /// The eyedropper: `x` and `y` in photo pixels, or `auto` to estimate the color from the
+    /// border again. `distance` optionally sets the key distance too.
+    fn pick_background(&mut self, query: &str) -> Result<(), Failure> {
+        let session = self.session_mut()?;
+        let number = |name: &str| -> Result<Option<f32>, Failure> {
+            match query_value(query, name) {
+                Some(value) => value.parse().map(Some).map_err(|_| Failure::new(400, &format!("invalid {}: {}", name, value))),
+                None => Ok(None),
+            }
+        };
+        if query.split('&').any(|pair| pair == "auto") {
+            session.options.background = Background::Auto;
+        } else if let (Some(x), Some(y)) = (number("x")?, number("y")?) {
+            // the color keying will see, after flat field correction
+            let colors = keyed_colors(&Photo::from(session.image.clone()), &session.options)?;
+            session.options.background = Background::Color(average_color(&colors, x, y)?);
+        }
+        if let Some(distance) = number("distance")? {
+            session.options.key_distance = Some(distance);
+        }
+        session.rescan();
+        Ok(())
+    }
+
+    fn edit_manifest(&mut self, edit: ManifestEdit) -> Result<(), Failure> {
+        let manifest = self.session_mut()?.manifest.as_mut().ok_or_else(|| Failure::new(409, "no fish to edit"))?;
+        if let Some(scale) = edit.scale {
+            if scale.iter().any(|s| s.is_nan() || *s <= 0.0) {
+                return Err(Failure::new(400, "the scale has to be positive"));
+            }
+            manifest.appearance.scale = scale;
+        }
+        if let Some(swarm_factor) = edit.swarm_factor {
+            if !(0.0..=1.0).contains(&swarm_factor) {
+                return Err(Failure::new(400, "the swarm factor has to be in [0,1]"));
+            }
+            manifest.behaviour.swarm_factor = swarm_factor;
+        }
+        manifest.update_school_size();
+        Ok(())
+    }
+
+    fn publish(&mut self) -> Result<(), Failure> {
+        let out_dir = self.out_dir.clone();
+        let session = self.session_mut()?;
+        let skin = session.skin.as_ref().map_err(|err| Failure::new(409, &err.to_string()))?;
+        let manifest = session.manifest.as_ref().ok_or_else(|| Failure::new(409, "no fish to publish"))?;
+        let number = match session.published {
+            Some(number) => number,
+            None => next_fish_number(&out_dir)?,
+        };
+        publish(skin, manifest, &out_dir, number)?;
+        session.published = Some(number);
+        Ok(())
+    }
+
+    fn preview<F: FnOnce(&FishSkin) -> DynamicImage>(&self, render: F) -> Result<Reply, Failure> {
+        let skin = self.session()?.skin.as_ref().map_err(|err| Failure::new(404, &err.to_string()))?;
+        let mut png = Vec::new();
+        render(skin).write_to(&mut png, ImageOutputFormat::Png).map_err(ScanError::from)?;
+        Ok(Reply::Data(png, "image/png"))
+    }
+
+    /// What the page shows.
+    fn state(&self) -> Result<Reply, Failure> {
+        let mut state = State {
+            capture: self.capture.is_some(),
+            photo: None,
+            background: None,
+            key_distance: None,
+            error: None,
+            warnings: Vec::new(),
+            manifest: None,
+            published: None,
+        };
+        if let Some(session) = &self.session {
+            let (width, height) = session.image.dimensions();
+            let name = session.photo.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            state.photo = Some(PhotoInfo { name, width, height });
+            match &session.skin {
+                Ok(skin) => {
+                    state.background = skin.key.map(|key| key.color.0);
+                    state.key_distance = skin.key.map(|key| key.distance);
+                    state.warnings = skin.quality.warnings().map(|finding| finding.message.clone()).collect();
+                }
+                Err(err) => state.error = Some(err.to_string()),
+            }
+            state.manifest = session.manifest.as_ref();
+            state.published = session.published;
+        }
+        let json = serde_json::to_string(&state).map_err(|err| Failure::new(500, &err.to_string()))?;
+        Ok(Reply::Json(json))
+    }
+}
+
+impl Session {
+    /// Scans the photo again with the session's options, keeping the operator's manifest edits.
+    fn rescan(&mut self) {
+        self.skin = fish_skin_from_image(self.image.clone(), &self.options);
+        if let Ok(skin) = &self.skin {
+            let mut manifest = FishManifest::new(skin, Some(&self.photo));
+            if let Some(edited) = &self.manifest {
+                manifest.appearance = edited.appearance.clone();
+                manifest.behaviour.swarm_factor = edited.behaviour.swarm_factor;
+                manifest.update_school_size();
+            }
+            self.manifest = Some(manifest);
+        }
+    }
+}
+
+/// Only the styler's own page may change anything: other web pages open in the booth's browser
+/// send their own `Origin` along, and pages that rebind their domain name to the styler's
+/// address still send their name as `Host`. Requests without `Origin` don't come from a page.
+fn check_origin(request: &Request) -> Result<(), Failure> {
+    let header = |name: &'static str| request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str());
+    let host = header("Host").unwrap_or("");
+    if !is_address(host) {
+        return Err(Failure::new(403, &format!("unknown host {}, open the styler by its ip address or as localhost", host)));
+    }
+    match header("Origin") {
+        Some(origin) if *request.method() != Method::Get && origin != format!("http://{}", host) => {
+            Err(Failure::new(403, &format!("requests from {} are not allowed", origin)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// true for `localhost` and ip addresses, with or without port
+fn is_address(host: &str) -> bool {
+    let name = match host.rfind(':') {
+        // an ipv6 address without port has colons too
+        Some(colon) if !host.ends_with(']') && host[..colon].parse::<Ipv6Addr>().is_err() => &host[..colon],
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name == "localhost" || name.parse::<IpAddr>().is_ok()
+}
+
+fn data_response(status: u16, data: Vec<u8>, content_type: &str) -> Response<io::Cursor<Vec<u8>>> {
+    let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+    Response::from_data(data).with_status_code(status).with_header(header)
+}
+
+/// url decoded `value` of the first `name=value` in a query string
+fn query_value(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key == name => Some(url_decode(value)),
+            _ => None,
+        }
+    })
+}
+
+/// `%20` and `+` to spaces and so on, broken escapes are kept as they are
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A file name for an upload that can't clash with earlier photos or leave the photo folder:
+/// the time, followed by the uploaded name without anything unusual in it.
+fn upload_name(name: &str) -> String {
+    let name: String = name
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c == ' ' { '_' } else { c })
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-' || *c == '_')
+        .collect();
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("{}-{}", time, name.trim_start_matches('.'))
+}
+
+/// mean color of the pixels around (`x`, `y`)
+fn average_color(image: &Rgb16Image, x: f32, y: f32) -> Result<Rgb<u8>, Failure> {
+    let (width, height) = image.dimensions();
+    if !(0.0..width as f32).contains(&x) || !(0.0..height as f32).contains(&y) {
+        return Err(Failure::new(400, "the picked point is outside the photo"));
+    }
+    let (x, y) = (x as u32, y as u32);
+    let mut sum = [0u64; 3];
+    let mut count = 0;
+    for py in y.saturating_sub(PICK_RADIUS)..(y + PICK_RADIUS + 1).min(height) {
+        for px in x.saturating_sub(PICK_RADIUS)..(x + PICK_RADIUS + 1).min(width) {
+            let pixel = image.get_pixel(px, py);
+            for c in 0..3 {
+                sum[c] += pixel[c] as u64;
+            }
+            count += 1;
+        }
+    }
+    Ok(Rgb(sum.map(|s| to_u8(((s + count / 2) / count) as u16))))
+}
@@ -0,0 +1,383 @@
+//! Printable coloring pages with registration marks, and cutting the fish out of photos of them.
+//!
+//! The page is A4 landscape. All sizes are in millimeters, but only their ratios matter:
+//! the page can be printed at any scale.
+//! Each corner has a square mark: a black ring with a dot in the middle. The dot of the top left
+//! mark is smaller, which tells the scanner which way up the page was photographed.
+
+use image::{
+    imageops,
+    imageops::FilterType,
+    DynamicImage,
+    ImageBuffer,
+    Luma,
+    Rgb,
+    RgbImage,
+};
+
+use crate::{
+    debug::DebugDump,
+    error::ScanError,
+    input::{
+        MaskImage,
+        Photo,
+        Rgb16Image,
+    },
+    morphology::Bitmap,
+    perspective::{
+        warp,
+        Homography,
+    },
+};
+
+use std::{
+    f32::consts::PI,
+    fmt::Write,
+    fs,
+    path::Path,
+};
+
+pub const PAGE_WIDTH: f32 = 297.0;
+pub const PAGE_HEIGHT: f32 = 210.0;
+/// a mark is 7 x 7 modules: ring 1 module thick, then a white ring, then the dot
+const MODULE: f32 = 2.5;
+const MARK_MARGIN: f32 = 10.0;
+/// the part of the page that is cut out: left, top, width, height
+const FISH_REGION: (f32, f32, f32, f32) = (34.0, 30.0, 229.0, 150.0);
+const OUTLINE_WIDTH: f32 = 0.8;
+/// the photo is searched for marks at about this size
+const DETECTION_SIZE: u32 = 1600;
+/// upper limit for the resolution of the cut out region, more doesn't add detail
+const MAX_PIXELS_PER_MM: f32 = 20.0;
+
+/// centers of the marks, clockwise from the top left
+fn mark_centers() -> [(f32, f32); 4] {
+    let near = MARK_MARGIN + 3.5 * MODULE;
+    [(near, near), (PAGE_WIDTH - near, near), (PAGE_WIDTH - near, PAGE_HEIGHT - near), (near, PAGE_HEIGHT - near)]
+}
+
+/// The fish to color, head to the right like `sea` expects: an oval body and a forked tail.
+/// Page coordinates, closed.
+pub fn fish_outline() -> Vec<(f32, f32)> {
+    let (left, top, _, _) = FISH_REGION;
+    let (cx, cy, rx, ry) = (left + 135.0, top + 75.0, 88.0, 55.0);
+    // the tail starts this far (radians) from the back of the body
+    let waist = 0.5;
+    let steps = 120;
+    let mut outline: Vec<(f32, f32)> = (0..=steps).map(|i| {
+        let angle = -(PI - waist) + 2.0 * (PI - waist) * i as f32 / steps as f32;
+        (cx + rx * angle.cos(), cy + ry * angle.sin())
+    }).collect();
+    outline.extend([(left + 8.0, top + 128.0), (left + 30.0, top + 75.0), (left + 8.0, top + 22.0)]);
+    outline
+}
+
+/// the eye: center and radius, drawn but not part of the silhouette
+fn eye() -> ((f32, f32), f32) {
+    let (left, top, _, _) = FISH_REGION;
+    ((left + 185.0, top + 60.0), 9.0)
+}
+
+/// `(left, top, size)` of the rings and dots of a mark
+fn mark_squares(center: (f32, f32), key: bool) -> [(f32, f32, f32); 3] {
+    let square = |modules: f32| (center.0 - modules * MODULE / 2.0, center.1 - modules * MODULE / 2.0, modules * MODULE);
+    [square(7.0), square(5.0), square(if key { 1.0 } else { 3.0 })]
+}
+
+/// The coloring page as svg, in millimeters.
+pub fn render_svg() -> String {
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}mm" height="{h}mm" viewBox="0 0 {w} {h}">"#, w = PAGE_WIDTH, h = PAGE_HEIGHT);
+    let _ = writeln!(svg, r#"  <rect width="{}" height="{}" fill="white"/>"#, PAGE_WIDTH, PAGE_HEIGHT);
+    for (i, &center) in mark_centers().iter().enumerate() {
+        for (square, color) in mark_squares(center, i == 0).iter().zip(["black", "white", "black"]) {
+            let _ = writeln!(svg, r#"  <rect x="{}" y="{}" width="{s}" height="{s}" fill="{}"/>"#, square.0, square.1, color, s = square.2);
+        }
+    }
+    let points: Vec<String> = fish_outline().iter().map(|(x, y)| format!("{:.2},{:.2}", x, y)).collect();
+    let _ = writeln!(svg, r#"  <polygon points="{}" fill="none" stroke="black" stroke-width="{}" stroke-linejoin="round"/>"#, points.join(" "), OUTLINE_WIDTH);
+    let ((ex, ey), radius) = eye();
+    let _ = writeln!(svg, r#"  <circle cx="{}" cy="{}" r="{}" fill="none" stroke="black" stroke-width="{}"/>"#, ex, ey, radius, OUTLINE_WIDTH);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// The coloring page as image, `dpi` pixels per inch.
+pub fn render_png(dpi: f32) -> RgbImage {
+    let scale = dpi / 25.4;
+    let width = (PAGE_WIDTH * scale).round() as u32;
+    let height = (PAGE_HEIGHT * scale).round() as u32;
+    let mut page = ImageBuffer::from_pixel(width, height, Rgb([255u8, 255, 255]));
+    let black = Rgb([0u8, 0, 0]);
+    let white = Rgb([255u8, 255, 255]);
+
+    for (i, &center) in mark_centers().iter().enumerate() {
+        for (square, &color) in mark_squares(center, i == 0).iter().zip(&[black, white, black]) {
+            let (x0, y0) = ((square.0 * scale).round() as u32, (square.1 * scale).round() as u32);
+            let (x1, y1) = (((square.0 + square.2) * scale).round() as u32, ((square.1 + square.2) * scale).round() as u32);
+            for y in y0..y1.min(height) {
+                for x in x0..x1.min(width) {
+                    page.put_pixel(x, y, color);
+                }
+            }
+        }
+    }
+
+    let outline = fish_outline();
+    let ((ex, ey), radius) = eye();
+    let half_stroke = OUTLINE_WIDTH / 2.0;
+    let (left, top, region_width, region_height) = FISH_REGION;
+    for y in (top * scale) as u32..((top + region_height) * scale) as u32 {
+        for x in (left * scale) as u32..((left + region_width) * scale) as u32 {
+            let p = ((x as f32 + 0.5) / scale, (y as f32 + 0.5) / scale);
+            let on_outline = outline.iter().zip(outline.iter().cycle().skip(1))
+                .any(|(&a, &b)| segment_distance(p, a, b) <= half_stroke);
+            let on_eye = ((p.0 - ex).hypot(p.1 - ey) - radius).abs() <= half_stroke;
+            if on_outline || on_eye {
+                page.put_pixel(x, y, black);
+            }
+        }
+    }
+    page
+}
+
+fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    let t = if length > 0.0 { (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length).clamp(0.0, 1.0) } else { 0.0 };
+    (p.0 - a.0 - t * dx).hypot(p.1 - a.1 - t * dy)
+}
+
+/// Writes the coloring page, as svg if `path` ends in `.svg`, else as png with `dpi`.
+pub fn save<P: AsRef<Path>>(path: P, dpi: f32) -> Result<(), ScanError> {
+    let path = path.as_ref();
+    let svg = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+    if svg {
+        fs::write(path, render_svg())?;
+    } else {
+        if !(36.0..=1200.0).contains(&dpi) {
+            return Err(ScanError::InvalidOption(format!("template dpi {} is not in [36, 1200]", dpi)));
+        }
+        render_png(dpi).save(path)?;
+    }
+    Ok(())
+}
+
+/// A mark candidate in the photo: a dark ring with a dark dot in its middle.
+#[derive(Debug, Clone, Copy)]
+struct Mark {
+    center: (f32, f32),
+    size: f32,
+    /// dot area / ring area, small for the top left mark
+    dot_ratio: f32,
+}
+
+/// Finds the four registration marks, clockwise from the top left of the page, in photo pixels.
+pub fn find_marks(img: &Rgb16Image, debug: &mut DebugDump) -> Result<[(f32, f32); 4], ScanError> {
+    let (width, height) = img.dimensions();
+    let scale = (DETECTION_SIZE as f32 / width.max(height) as f32).min(1.0);
+    let grey = imageops::colorops::grayscale(img);
+    let small = imageops::resize(
+        &grey,
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+        FilterType::Triangle,
+    );
+    // dark compared to the surroundings, so shadows across the page don't matter
+    let (small_width, small_height) = small.dimensions();
+    let surroundings = imageops::blur(&small, small_width.max(small_height) as f32 / 25.0);
+    let dark = Bitmap {
+        width: small_width,
+        height: small_height,
+        data: small.pixels().zip(surroundings.pixels()).map(|(p, s)| (p[0] as u32) * 10 < (s[0] as u32) * 7).collect(),
+    };
+    debug.add("mark threshold", || DynamicImage::ImageLuma16(dark.to_mask()));
+
+    let (labels, components) = dark.components();
+    let mut sums = vec![(0f64, 0f64); components.len()];
+    for (i, &label) in labels.iter().enumerate() {
+        if label != 0 {
+            let sum = &mut sums[label as usize - 1];
+            sum.0 += (i as u32 % small_width) as f64;
+            sum.1 += (i as u32 / small_width) as f64;
+        }
+    }
+    let centroid = |i: usize| ((sums[i].0 / components[i].area as f64) as f32, (sums[i].1 / components[i].area as f64) as f32);
+
+    let mut marks = Vec::new();
+    for (r, ring) in components.iter().enumerate() {
+        let (w, h) = (ring.bbox.width() as f32, ring.bbox.height() as f32);
+        let fill = ring.area as f32 / (w * h);
+        if w < 10.0 || h < 10.0 || w / h > 2.0 || h / w > 2.0 || !(0.2..=0.75).contains(&fill) {
+            continue;
+        }
+        let ring_center = centroid(r);
+        let size = w.max(h);
+        // the biggest dot sitting in the middle of the ring
+        let dot = components.iter().enumerate()
+            .filter(|&(d, dot)| {
+                let (x, y) = centroid(d);
+                d != r && (x - ring_center.0).hypot(y - ring_center.1) < 0.15 * size
+                    && (0.02..=0.7).contains(&(dot.area as f32 / ring.area as f32))
+            })
+            .max_by_key(|(_, dot)| dot.area);
+        if let Some((d, dot)) = dot {
+            let dot_center = centroid(d);
+            let total = (ring.area + dot.area) as f32;
+            let center = (
+                (ring_center.0 * ring.area as f32 + dot_center.0 * dot.area as f32) / total,
+                (ring_center.1 * ring.area as f32 + dot_center.1 * dot.area as f32) / total,
+            );
+            marks.push(Mark { center, size, dot_ratio: dot.area as f32 / ring.area as f32 });
+        }
+    }
+    if marks.len() < 4 {
+        return Err(ScanError::MarksNotFound(marks.len()));
+    }
+
+    // the eye of a colored fish can look like a mark too. The real ones are the four
+    // of similar size that span the biggest area
+    marks.sort_by(|a, b| b.size.partial_cmp(&a.size).unwrap());
+    marks.truncate(24);
+    let mut best: Option<([Mark; 4], f32)> = None;
+    let n = marks.len();
+    for a in 0..n {
+        for b in a + 1..n {
+            for c in b + 1..n {
+                for d in c + 1..n {
+                    let four = clockwise([marks[a], marks[b], marks[c], marks[d]]);
+                    let sizes = four.iter().map(|m| m.size);
+                    let (min, max) = (sizes.clone().fold(f32::MAX, f32::min), sizes.fold(0.0, f32::max));
+                    if max > 2.0 * min {
+                        continue;
+                    }
+                    let area = polygon_area(&four.map(|m| m.center));
+                    if best.as_ref().is_none_or(|(_, best_area)| area > *best_area) {
+                        best = Some((four, area));
+                    }
+                }
+            }
+        }
+    }
+    let four = match best {
+        Some((four, _)) => four,
+        None => return Err(ScanError::MarksNotFound(0)),
+    };
+
+    // the top left mark has the smallest dot, clearly smaller than the others
+    let mut by_dot: Vec<usize> = (0..4).collect();
+    by_dot.sort_by(|&a, &b| four[a].dot_ratio.partial_cmp(&four[b].dot_ratio).unwrap());
+    if four[by_dot[0]].dot_ratio * 2.0 > four[by_dot[1]].dot_ratio {
+        return Err(ScanError::InvalidOption("can't tell which registration mark is the top left one".to_string()));
+    }
+    let first = by_dot[0];
+    let mut corners = [(0.0, 0.0); 4];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let c = four[(first + i) % 4].center;
+        *corner = (c.0 / scale, c.1 / scale);
+    }
+    Ok(corners)
+}
+
+/// sorted clockwise (on screen, y pointing down) around their middle
+fn clockwise(mut marks: [Mark; 4]) -> [Mark; 4] {
+    let cx = marks.iter().map(|m| m.center.0).sum::<f32>() / 4.0;
+    let cy = marks.iter().map(|m| m.center.1).sum::<f32>() / 4.0;
+    let angle = |m: &Mark| (m.center.1 - cy).atan2(m.center.0 - cx);
+    marks.sort_by(|a, b| angle(a).partial_cmp(&angle(b)).unwrap());
+    marks
+}
+
+fn polygon_area(points: &[(f32, f32)]) -> f32 {
+    let twice: f32 = points.iter().zip(points.iter().cycle().skip(1)).map(|(a, b)| a.0 * b.1 - b.0 * a.1).sum();
+    twice.abs() / 2.0
+}
+
+/// Cuts the fish region out of a photo of the coloring page and straightens it.
+/// The alpha channel of the result is the printed fish's silhouette.
+pub fn rectify(img: &Rgb16Image, debug: &mut DebugDump) -> Result<Photo, ScanError> {
+    let marks = find_marks(img, debug)?;
+    let centers = mark_centers();
+
+    // about as many pixels per millimeter as the photo has along the top edge
+    let top_px = (marks[1].0 - marks[0].0).hypot(marks[1].1 - marks[0].1);
+    let pixels_per_mm = (top_px / (centers[1].0 - centers[0].0)).clamp(1.0, MAX_PIXELS_PER_MM);
+    let (left, top, region_width, region_height) = FISH_REGION;
+    let to_region = |(x, y): (f32, f32)| ((x - left) * pixels_per_mm - 0.5, (y - top) * pixels_per_mm - 0.5);
+    let to_photo = Homography::from_points(&centers.map(to_region), &marks).ok_or_else(|| {
+        ScanError::InvalidOption("the registration marks are on a line".to_string())
+    })?;
+
+    let width = (region_width * pixels_per_mm).round() as u32;
+    let height = (region_height * pixels_per_mm).round() as u32;
+    let rgb = warp(img, &to_photo, width, height, Rgb([u16::MAX; 3]));
+    let outline: Vec<(f32, f32)> = fish_outline().into_iter().map(|p| {
+        let (x, y) = to_region(p);
+        (x + 0.5, y + 0.5)
+    }).collect();
+    let alpha = fill_polygon(&outline, width, height);
+    debug.add("rectified page", || DynamicImage::ImageRgb16(rgb.clone()));
+    Ok(Photo { rgb, alpha: Some(alpha) })
+}
+
+/// Antialiased coverage of a polygon (pixel coordinates, corners of pixels at integers),
+/// 4 x 4 samples per pixel.
+fn fill_polygon(polygon: &[(f32, f32)], width: u32, height: u32) -> MaskImage {
+    const SAMPLES: u32 = 4;
+    let mut coverage = vec![0u32; (width * height) as usize];
+    let mut crossings = Vec::new();
+    for sample_row in 0..height * SAMPLES {
+        let y = (sample_row as f32 + 0.5) / SAMPLES as f32;
+        crossings.clear();
+        for (&a, &b) in polygon.iter().zip(polygon.iter().cycle().skip(1)) {
+            if (a.1 <= y) != (b.1 <= y) {
+                crossings.push(a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0));
+            }
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let row = (sample_row / SAMPLES * width) as usize;
+        for span in crossings.chunks(2) {
+            if let [from, to] = *span {
+                // samples at x = (i + 0.5) / SAMPLES inside [from, to)
+                let first = (from * SAMPLES as f32 - 0.5).ceil().max(0.0) as u32;
+                let end = ((to * SAMPLES as f32 - 0.5).ceil().max(0.0) as u32).min(width * SAMPLES);
+                for sample in first..end {
+                    coverage[row + (sample / SAMPLES) as usize] += 1;
+                }
+            }
+        }
+    }
+    let full = SAMPLES * SAMPLES;
+    ImageBuffer::from_fn(width, height, |x, y| {
+        Luma([(coverage[(y * width + x) as usize] * u16::MAX as u32 / full) as u16])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_marks(found: [(f32, f32); 4], expected: [(f32, f32); 4]) {
+        for (found, expected) in found.iter().zip(&expected) {
+            assert!(
+                (found.0 - expected.0).hypot(found.1 - expected.1) < 1.5,
+                "found a mark at {:?}, expected {:?}", found, expected,
+            );
+        }
+    }
+
+    #[test]
+    fn rendered_marks_are_found() {
+        let dpi = 50.0;
+        let scale = dpi / 25.4;
+        let page = DynamicImage::ImageRgb8(render_png(dpi)).to_rgb16();
+        let (width, height) = page.dimensions();
+        let expected = mark_centers().map(|(x, y)| (x * scale, y * scale));
+        assert_marks(find_marks(&page, &mut DebugDump::new(None)).unwrap(), expected);
+
+        // upside down the small dot still tells the top left
+        let turned = imageops::rotate180(&page);
+        let expected = expected.map(|(x, y)| (width as f32 - x, height as f32 - y));
+        assert_marks(find_marks(&turned, &mut DebugDump::new(None)).unwrap(), expected);
+    }
+}
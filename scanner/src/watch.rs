@@ -0,0 +1,244 @@
+//! Watch folder ingestion: photos dropped into a directory (e.g. by gphoto2) get scanned
+//! as soon as they are completely written.
+
+use crate::{
+    batch::{
+        scan_photo,
+        BatchEntry,
+    },
+    error::ScanError,
+    options::ScanOptions,
+    output::{
+        is_image_file,
+        next_fish_number,
+    },
+};
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+        UNIX_EPOCH,
+    },
+};
+
+/// name of the state file that is kept in the watched directory by default
+pub const STATE_FILE_NAME: &str = ".scanner-watch";
+
+/// Size and modification time, a file counts as unchanged as long as both stay the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    modified: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Stamp> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(Stamp { size: metadata.len(), modified })
+    }
+}
+
+/// The photos that have been published, persisted as one line per file:
+/// `<mtime>\t<size>\t<fish number>\t<file name>`, the first fish's number if the photo had several
+struct WatchState {
+    path: PathBuf,
+    done: HashMap<String, (Stamp, u32)>,
+}
+
+impl WatchState {
+    fn load(path: PathBuf) -> io::Result<WatchState> {
+        let mut done = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for line in content.lines() {
+                    let fields: Vec<&str> = line.splitn(4, '\t').collect();
+                    if fields.len() != 4 {
+                        continue;
+                    }
+                    // failures were written as `-` by earlier versions, they're tried again
+                    if let (Ok(modified), Ok(size), Ok(number)) = (fields[0].parse(), fields[1].parse(), fields[2].parse()) {
+                        done.insert(fields[3].to_string(), (Stamp { size, modified }, number));
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        Ok(WatchState { path, done })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut names: Vec<&String> = self.done.keys().collect();
+        names.sort();
+        let mut content = String::new();
+        for name in names {
+            let (stamp, number) = self.done[name];
+            content.push_str(&format!("{}\t{}\t{:04}\t{}\n", stamp.modified, stamp.size, number, name));
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Polls a directory for new photos and publishes their skins into an output directory.
+pub struct Watcher {
+    dir: PathBuf,
+    out_dir: PathBuf,
+    state: WatchState,
+    /// files that showed up but may still be written to, with the time their stamp last changed
+    pending: HashMap<PathBuf, (Stamp, Instant)>,
+    /// photos that gave no fish, tried again when they change or after a restart
+    failed: HashMap<PathBuf, Stamp>,
+    /// how long a file's size and mtime have to stay the same before it's considered complete
+    pub settle_time: Duration,
+}
+
+impl Watcher {
+    /// `state_file` defaults to `STATE_FILE_NAME` in `dir`.
+    /// `out_dir` has to be another directory, the skins published there are images too.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, out_dir: Q, state_file: Option<PathBuf>) -> io::Result<Watcher> {
+        let dir = dir.as_ref().to_path_buf();
+        let out_dir = out_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&out_dir)?;
+        if fs::canonicalize(&dir)? == fs::canonicalize(&out_dir)? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the output directory can't be the watched one"));
+        }
+        let state_file = state_file.unwrap_or_else(|| dir.join(STATE_FILE_NAME));
+        Ok(Watcher {
+            state: WatchState::load(state_file)?,
+            dir,
+            out_dir,
+            pending: HashMap::new(),
+            failed: HashMap::new(),
+            settle_time: Duration::from_secs(2),
+        })
+    }
+
+    /// Looks at the directory once and scans every photo that is new and complete.
+    pub fn poll(&mut self, options: &ScanOptions) -> io::Result<Vec<BatchEntry>> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if !name.starts_with('.') => name.to_string(),
+                _ => continue,
+            };
+            if !path.is_file() || !is_image_file(&path) {
+                continue;
+            }
+            // the file may vanish between listing and looking at it
+            let stamp = match Stamp::of(&path) {
+                Ok(stamp) => stamp,
+                Err(_) => continue,
+            };
+            if matches!(self.state.done.get(&name), Some((done, _)) if *done == stamp) || self.failed.get(&path) == Some(&stamp) {
+                continue;
+            }
+            match self.pending.get(&path) {
+                Some((seen, since)) if *seen == stamp => {
+                    if now.duration_since(*since) >= self.settle_time {
+                        ready.push((path, name, stamp));
+                    }
+                }
+                _ => {
+                    self.pending.insert(path, (stamp, now));
+                }
+            }
+        }
+        ready.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut entries = Vec::new();
+        for (path, name, stamp) in ready {
+            self.pending.remove(&path);
+            let mut number = next_fish_number(&self.out_dir)?;
+            let scanned = scan_photo(&path, options, &self.out_dir, &mut number);
+            match scanned.iter().find_map(|entry| entry.result.as_ref().ok().copied()) {
+                Some(first) => {
+                    self.failed.remove(&path);
+                    self.state.done.insert(name, (stamp, first));
+                    self.state.save()?;
+                }
+                // an i/o error may be gone by the next poll, a photo that can't be scanned
+                // has to change first
+                None if scanned.iter().all(|entry| matches!(entry.result, Err(ScanError::Io(_)))) => (),
+                None => {
+                    self.failed.insert(path, stamp);
+                }
+            }
+            entries.extend(scanned);
+        }
+        Ok(entries)
+    }
+
+    /// Polls every `interval` until an i/o error occurs, `on_entry` is called for each scanned photo (each fish with `ScanOptions::multiple_fish`).
+    pub fn run<F: FnMut(&BatchEntry)>(&mut self, options: &ScanOptions, interval: Duration, mut on_entry: F) -> io::Result<()> {
+        loop {
+            let started = Instant::now();
+            for entry in self.poll(options)? {
+                on_entry(&entry);
+            }
+            let elapsed = started.elapsed();
+            if elapsed < interval {
+                thread::sleep(interval - elapsed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory of its own for every test, and for every test run
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("scanner-watch-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn state_lines() {
+        let path = scratch_dir("lines").join(STATE_FILE_NAME);
+        fs::write(&path, [
+            "1700000000\t52000\t0003\tfish.jpg",
+            // a failure as earlier versions wrote it, tried again
+            "1700000100\t41000\t-\tbroken.jpg",
+            "1700000200\t30000\t0004\tname with\ttab.png",
+            "not a line",
+            "",
+        ].join("\n")).unwrap();
+        let state = WatchState::load(path).unwrap();
+        assert_eq!(state.done.len(), 2);
+        assert_eq!(state.done["fish.jpg"], (Stamp { size: 52000, modified: 1_700_000_000 }, 3));
+        assert_eq!(state.done["name with\ttab.png"], (Stamp { size: 30000, modified: 1_700_000_200 }, 4));
+        assert!(!state.done.contains_key("broken.jpg"));
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = scratch_dir("round-trip");
+        let mut state = WatchState::load(dir.join(STATE_FILE_NAME)).unwrap();
+        assert!(state.done.is_empty());
+        state.done.insert("b.jpg".to_string(), (Stamp { size: 2, modified: 20 }, 12));
+        state.done.insert("a.jpg".to_string(), (Stamp { size: 1, modified: 10 }, 11));
+        state.save().unwrap();
+        assert_eq!(fs::read_to_string(dir.join(STATE_FILE_NAME)).unwrap(), "10\t1\t0011\ta.jpg\n20\t2\t0012\tb.jpg\n");
+        assert_eq!(WatchState::load(dir.join(STATE_FILE_NAME)).unwrap().done, state.done);
+    }
+}
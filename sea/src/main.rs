@@ -443,7 +443,7 @@ fn main() {
     let (fish_colors_texture, fish_colors_future) = {
         let img_dim = fish_colors.dimensions();
         match ImmutableImage::from_iter(
-            fish_colors.as_rgba8().unwrap().pixels().map(|rgba| {
+            fish_colors.to_rgba8().pixels().map(|rgba| {
                 let bytes : [u8; 4] = [rgba[0], rgba[1], rgba[2], rgba[3]]; 
                 bytes
             }),
@@ -459,7 +459,7 @@ fn main() {
     let (fish_normals_texture, fish_normals_future) = {
         let img_dim = fish_normals.dimensions();
         match ImmutableImage::from_iter(
-            fish_normals.as_rgb8().unwrap().pixels().map(|rgb| {
+            fish_normals.to_rgb8().pixels().map(|rgb| {
                 let bytes : [u8; 4] = [rgb[0], rgb[1], rgb[2], 255]; 
                 bytes
             }),
@@ -38,7 +38,6 @@ void main() {
 		discard;
 	} else {
 		vec4 normal = (texture(fish_normals, uv) - 0.5) * 2;
-		normal.x = -normal.x; //well, for some reason...  coordinate systems... don't think just check... (nooot)
 		if(!gl_FrontFacing) {
 			normal.z = -normal.z;
 		}